@@ -0,0 +1,533 @@
+//! Deterministic archives of a directory, used as initrd/ramdisk modules.
+//!
+//! All timestamps are zero, owners are root and permissions are normalized to `0755` for
+//! directories and executables and `0644` for all other files, so the same directory always
+//! produces the same archive.
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// The format of an archive built from a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// A POSIX ustar archive.
+    Ustar,
+    /// A cpio archive in the SVR4 `newc` format, as used by Linux initramfs.
+    Newc,
+    /// The regular files concatenated after a simple index.
+    ///
+    /// The archive starts with the magic `GBIDX001` and the number of files as a `u64`.
+    /// For every file follow its offset and size in the archive, the length of its path and
+    /// the path itself, padded to 8 bytes. File data is 8-byte aligned. All integers are
+    /// little-endian `u64`s and paths are relative with `/` as separator.
+    Concat,
+}
+
+impl ArchiveFormat {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "ustar" | "tar" => Ok(ArchiveFormat::Ustar),
+            "newc" | "cpio" => Ok(ArchiveFormat::Newc),
+            "concat" => Ok(ArchiveFormat::Concat),
+            other => Err(anyhow!(
+                "unknown archive format `{}` (expected `ustar`, `newc` or `concat`)",
+                other
+            )),
+        }
+    }
+
+    /// The file extension of archives in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Ustar => "tar",
+            ArchiveFormat::Newc => "cpio",
+            ArchiveFormat::Concat => "img",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum EntryKind {
+    Dir,
+    File(PathBuf),
+    Symlink(String),
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Path relative to the archived directory, with `/` as separator.
+    name: String,
+    kind: EntryKind,
+    mode: u32,
+    size: u64,
+}
+
+/// Archives the contents of `dir` in `format` and writes the archive to `out`.
+pub fn write_archive(dir: &Path, format: ArchiveFormat, out: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(anyhow!("archive directory `{}` not found", dir.display()));
+    }
+    let mut entries = Vec::new();
+    collect(dir, "", &mut entries)?;
+
+    let file =
+        fs::File::create(out).with_context(|| format!("Failed to create `{}`", out.display()))?;
+    let mut writer = BufWriter::new(file);
+    match format {
+        ArchiveFormat::Ustar => write_ustar(&entries, &mut writer),
+        ArchiveFormat::Newc => write_newc(&entries, &mut writer),
+        ArchiveFormat::Concat => write_concat(&entries, &mut writer),
+    }
+    .with_context(|| format!("Failed to archive `{}`", dir.display()))?;
+    writer
+        .flush()
+        .with_context(|| format!("Failed to write `{}`", out.display()))
+}
+
+/// Collects the entries below `dir` in a stable order, directories before their contents.
+fn collect(dir: &Path, prefix: &str, entries: &mut Vec<Entry>) -> Result<()> {
+    let mut children: Vec<_> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read `{}`", dir.display()))?
+        .collect::<Result<_, _>>()?;
+    children.sort_by_key(|entry| entry.file_name());
+
+    for child in children {
+        let file_name = child.file_name();
+        let file_name = file_name
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid file name `{}`", child.path().display()))?;
+        let name = format!("{}{}", prefix, file_name);
+        let path = child.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&path)?;
+            let target = target
+                .to_str()
+                .ok_or_else(|| anyhow!("Invalid symlink target in `{}`", path.display()))?
+                .to_owned();
+            entries.push(Entry {
+                name,
+                kind: EntryKind::Symlink(target),
+                mode: 0o777,
+                size: 0,
+            });
+        } else if metadata.is_dir() {
+            entries.push(Entry {
+                name: name.clone(),
+                kind: EntryKind::Dir,
+                mode: 0o755,
+                size: 0,
+            });
+            collect(&path, &format!("{}/", name), entries)?;
+        } else {
+            entries.push(Entry {
+                name,
+                kind: EntryKind::File(path),
+                mode: if is_executable(&metadata) {
+                    0o755
+                } else {
+                    0o644
+                },
+                size: metadata.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+fn write_ustar(entries: &[Entry], out: &mut impl Write) -> Result<()> {
+    for entry in entries {
+        let mut header = [0u8; 512];
+        let (name, prefix) = split_ustar_name(entry)?;
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let (type_flag, mode, size) = match &entry.kind {
+            EntryKind::Dir => (b'5', entry.mode, 0),
+            EntryKind::File(_) if entry.size >= 0o77777777777 => {
+                return Err(anyhow!("`{}` is too large for a ustar archive", entry.name))
+            }
+            EntryKind::File(_) => (b'0', entry.mode, entry.size),
+            EntryKind::Symlink(target) => {
+                if target.len() > 100 {
+                    return Err(anyhow!("symlink target of `{}` is too long", entry.name));
+                }
+                header[157..157 + target.len()].copy_from_slice(target.as_bytes());
+                (b'2', entry.mode, 0)
+            }
+        };
+        write_octal(&mut header[100..108], u64::from(mode));
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], size);
+        write_octal(&mut header[136..148], 0);
+        header[156] = type_flag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+        // The checksum is computed with the checksum field set to spaces.
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+        write_octal(&mut header[148..155], u64::from(checksum));
+        header[155] = b' ';
+
+        out.write_all(&header)?;
+        if let EntryKind::File(path) = &entry.kind {
+            copy_file(path, entry.size, out)?;
+            pad(out, entry.size as usize, 512)?;
+        }
+    }
+    // Two zero blocks mark the end of the archive.
+    out.write_all(&[0; 1024])?;
+    Ok(())
+}
+
+/// Splits the name of `entry` into the ustar `name` and `prefix` fields.
+fn split_ustar_name(entry: &Entry) -> Result<(String, String)> {
+    let name = match entry.kind {
+        EntryKind::Dir => format!("{}/", entry.name),
+        _ => entry.name.clone(),
+    };
+    if name.len() <= 100 {
+        return Ok((name, String::new()));
+    }
+    // Split at a `/` so that the prefix fits into 155 and the rest into 100 bytes. The
+    // trailing `/` of a directory would leave the name empty.
+    name.char_indices()
+        .filter(|&(index, c)| {
+            c == '/' && index <= 155 && (1..=100).contains(&(name.len() - index - 1))
+        })
+        .map(|(index, _)| (name[index + 1..].to_owned(), name[..index].to_owned()))
+        .next()
+        .ok_or_else(|| anyhow!("path `{}` is too long for a ustar archive", entry.name))
+}
+
+fn write_newc(entries: &[Entry], out: &mut impl Write) -> Result<()> {
+    let mut ino = 0;
+    for entry in entries {
+        ino += 1;
+        let (mode, nlink, data) = match &entry.kind {
+            EntryKind::Dir => (0o040000 | entry.mode, 2, None),
+            EntryKind::File(path) => (0o100000 | entry.mode, 1, Some(path)),
+            EntryKind::Symlink(_) => (0o120000 | entry.mode, 1, None),
+        };
+        let size = match &entry.kind {
+            EntryKind::Symlink(target) => target.len() as u64,
+            _ => entry.size,
+        };
+        write_newc_header(out, ino, mode, nlink, size, &entry.name)?;
+        match (&entry.kind, data) {
+            (_, Some(path)) => copy_file(path, size, out)?,
+            (EntryKind::Symlink(target), None) => out.write_all(target.as_bytes())?,
+            _ => {}
+        }
+        pad(out, size as usize, 4)?;
+    }
+    write_newc_header(out, 0, 0, 1, 0, "TRAILER!!!")?;
+    Ok(())
+}
+
+fn write_newc_header(
+    out: &mut impl Write,
+    ino: u32,
+    mode: u32,
+    nlink: u32,
+    size: u64,
+    name: &str,
+) -> Result<()> {
+    if size > u64::from(u32::MAX) {
+        return Err(anyhow!("`{}` is too large for a cpio archive", name));
+    }
+    let fields = [
+        ino,
+        mode,
+        0, // uid
+        0, // gid
+        nlink,
+        0, // mtime
+        size as u32,
+        0, // devmajor
+        0, // devminor
+        0, // rdevmajor
+        0, // rdevminor
+        name.len() as u32 + 1,
+        0, // check
+    ];
+    let mut header = String::from("070701");
+    for field in &fields {
+        header.push_str(&format!("{:08X}", field));
+    }
+    out.write_all(header.as_bytes())?;
+    out.write_all(name.as_bytes())?;
+    out.write_all(&[0])?;
+    pad(out, header.len() + name.len() + 1, 4)?;
+    Ok(())
+}
+
+fn write_concat(entries: &[Entry], out: &mut impl Write) -> Result<()> {
+    if let Some(entry) = entries
+        .iter()
+        .find(|entry| matches!(entry.kind, EntryKind::Symlink(_)))
+    {
+        return Err(anyhow!(
+            "`{}` is a symlink, which the concat format does not support",
+            entry.name
+        ));
+    }
+    let files: Vec<_> = entries
+        .iter()
+        .filter_map(|entry| match &entry.kind {
+            EntryKind::File(path) => Some((entry, path)),
+            _ => None,
+        })
+        .collect();
+
+    let index_len: u64 = 16
+        + files
+            .iter()
+            .map(|(entry, _)| 24 + align(entry.name.len() as u64, 8))
+            .sum::<u64>();
+    out.write_all(b"GBIDX001")?;
+    out.write_all(&(files.len() as u64).to_le_bytes())?;
+    let mut offset = index_len;
+    for (entry, _) in &files {
+        out.write_all(&offset.to_le_bytes())?;
+        out.write_all(&entry.size.to_le_bytes())?;
+        out.write_all(&(entry.name.len() as u64).to_le_bytes())?;
+        out.write_all(entry.name.as_bytes())?;
+        pad(out, entry.name.len(), 8)?;
+        offset += align(entry.size, 8);
+    }
+    for (entry, path) in &files {
+        copy_file(path, entry.size, out)?;
+        pad(out, entry.size as usize, 8)?;
+    }
+    Ok(())
+}
+
+/// Copies the file at `path` to `out`, failing if it no longer has the expected size.
+fn copy_file(path: &Path, size: u64, out: &mut impl Write) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read `{}`", path.display()))?;
+    if data.len() as u64 != size {
+        return Err(anyhow!(
+            "`{}` changed while it was archived",
+            path.display()
+        ));
+    }
+    out.write_all(&data)?;
+    Ok(())
+}
+
+/// Writes zeros after `len` bytes up to the next multiple of `alignment`.
+fn pad(out: &mut impl Write, len: usize, alignment: usize) -> Result<()> {
+    let padding = (alignment - len % alignment) % alignment;
+    out.write_all(&vec![0; padding])?;
+    Ok(())
+}
+
+fn align(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+/// Writes `value` as a NUL-terminated, zero-padded octal number filling `field`.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, TempDir};
+
+    /// A nested path longer than the 100 bytes of the ustar `name` field.
+    fn long_dir() -> String {
+        format!("{}/{}", "d".repeat(60), "e".repeat(60))
+    }
+
+    /// Creates a directory to archive and returns the files it contains with their data, in
+    /// archive order.
+    fn create_tree(root: &Path) -> Vec<(String, Vec<u8>)> {
+        let files = vec![
+            ("a.txt".to_owned(), b"hello".to_vec()),
+            ("bin/init".to_owned(), vec![0x7f; 513]),
+            (format!("{}/f.txt", long_dir()), b"nested".to_vec()),
+        ];
+        for (name, data) in &files {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        files
+    }
+
+    /// Archives `dir` twice and checks that both archives are identical.
+    fn archive(dir: &Path, format: ArchiveFormat) -> Vec<u8> {
+        let out = dir.with_extension(format.extension());
+        write_archive(dir, format, &out).unwrap();
+        let first = fs::read(&out).unwrap();
+        write_archive(dir, format, &out).unwrap();
+        assert_eq!(
+            first,
+            fs::read(&out).unwrap(),
+            "the archive is not deterministic"
+        );
+        fs::remove_file(&out).unwrap();
+        first
+    }
+
+    fn parse_octal(field: &[u8]) -> u64 {
+        let digits = std::str::from_utf8(field).unwrap();
+        u64::from_str_radix(digits.trim_end_matches(&['\0', ' '][..]), 8).unwrap()
+    }
+
+    fn c_string(field: &[u8]) -> String {
+        let len = field
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(field.len());
+        String::from_utf8(field[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn ustar_round_trip() {
+        let dir = TempDir::new("ustar");
+        let root = dir.path().join("root");
+        let files = create_tree(&root);
+        let archive = archive(&root, ArchiveFormat::Ustar);
+        assert_eq!(archive.len() % 512, 0);
+        assert!(archive[archive.len() - 1024..]
+            .iter()
+            .all(|&byte| byte == 0));
+
+        let mut found = Vec::new();
+        let mut offset = 0;
+        while archive[offset..offset + 512].iter().any(|&byte| byte != 0) {
+            let header = &archive[offset..offset + 512];
+            assert_eq!(&header[257..265], b"ustar\x0000");
+            let mut copy = header.to_vec();
+            copy[148..156].copy_from_slice(b"        ");
+            let checksum: u64 = copy.iter().map(|&byte| u64::from(byte)).sum();
+            assert_eq!(parse_octal(&header[148..156]), checksum);
+
+            let name = c_string(&header[..100]);
+            let prefix = c_string(&header[345..500]);
+            let name = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            let size = parse_octal(&header[124..136]) as usize;
+            offset += 512;
+            if header[156] == b'0' {
+                found.push((name, archive[offset..offset + size].to_vec()));
+            } else {
+                assert_eq!(header[156], b'5');
+                assert!(name.ends_with('/'));
+            }
+            offset += align(size as u64, 512) as usize;
+        }
+        assert_eq!(offset, archive.len() - 1024);
+        assert_eq!(found, files);
+    }
+
+    #[test]
+    fn ustar_name_split() {
+        let entry = |name: String| Entry {
+            name,
+            kind: EntryKind::Dir,
+            mode: 0o755,
+            size: 0,
+        };
+        assert_eq!(
+            split_ustar_name(&entry("boot".to_owned())).unwrap(),
+            ("boot/".to_owned(), String::new())
+        );
+        assert_eq!(
+            split_ustar_name(&entry(long_dir())).unwrap(),
+            (format!("{}/", "e".repeat(60)), "d".repeat(60))
+        );
+        assert!(split_ustar_name(&entry("x".repeat(101))).is_err());
+    }
+
+    #[test]
+    fn newc_round_trip() {
+        let dir = TempDir::new("newc");
+        let root = dir.path().join("root");
+        let files = create_tree(&root);
+        let archive = archive(&root, ArchiveFormat::Newc);
+
+        let field = |offset: usize, index: usize| {
+            let hex = std::str::from_utf8(&archive[offset + 6 + 8 * index..][..8]).unwrap();
+            u32::from_str_radix(hex, 16).unwrap() as usize
+        };
+        let mut found = Vec::new();
+        let mut offset = 0;
+        let trailer = loop {
+            assert_eq!(&archive[offset..offset + 6], b"070701");
+            let (mode, size, name_len) = (field(offset, 1), field(offset, 6), field(offset, 11));
+            let name = &archive[offset + 110..offset + 110 + name_len];
+            assert_eq!(name.last(), Some(&0));
+            let name = String::from_utf8(name[..name_len - 1].to_vec()).unwrap();
+            // The header and name, and the data are each padded to 4 bytes.
+            let data = align(110 + name_len as u64, 4) as usize + offset;
+            offset = data + align(size as u64, 4) as usize;
+            if name == "TRAILER!!!" {
+                break (mode, size);
+            }
+            if mode & 0o170000 == 0o100000 {
+                found.push((name, archive[data..data + size].to_vec()));
+            } else {
+                assert_eq!(mode, 0o040755);
+            }
+        };
+        assert_eq!(trailer, (0, 0));
+        assert_eq!(offset, archive.len());
+        assert_eq!(found, files);
+    }
+
+    #[test]
+    fn concat_round_trip() {
+        let dir = TempDir::new("concat");
+        let root = dir.path().join("root");
+        let files = create_tree(&root);
+        let archive = archive(&root, ArchiveFormat::Concat);
+
+        let u64_at = |offset: usize| test_util::u64_at(&archive, offset) as usize;
+        assert_eq!(&archive[..8], b"GBIDX001");
+        assert_eq!(u64_at(8), files.len());
+        let mut found = Vec::new();
+        let mut index = 16;
+        let mut end = 0;
+        for _ in 0..files.len() {
+            let (offset, size, name_len) = (u64_at(index), u64_at(index + 8), u64_at(index + 16));
+            let name = &archive[index + 24..index + 24 + name_len];
+            index += 24 + align(name_len as u64, 8) as usize;
+            assert_eq!(offset % 8, 0);
+            found.push((
+                String::from_utf8(name.to_vec()).unwrap(),
+                archive[offset..offset + size].to_vec(),
+            ));
+            end = offset + align(size as u64, 8) as usize;
+        }
+        // The data of the first file directly follows the index.
+        assert_eq!(u64_at(16), index);
+        assert_eq!(end, archive.len());
+        assert_eq!(found, files);
+    }
+}
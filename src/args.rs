@@ -0,0 +1,225 @@
+use anyhow::{anyhow, Context, Result};
+use std::{env, path::PathBuf};
+
+/// A parsed command line invocation.
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// Build the kernel and create a bootable image.
+    Build(Args),
+    /// Build the kernel and boot it in QEMU.
+    Run(Args),
+    /// Build the test executables and run each of them in QEMU.
+    Test(Args),
+    /// Invoked by cargo as a `runner` for an executable.
+    Runner(Args),
+    /// Remove the files created by grub-bootimage.
+    Clean(Args),
+    /// Check that the external tools needed by grub-bootimage are installed.
+    Doctor(Args),
+    /// Print help for a subcommand, or the general help if `None`.
+    Help(Option<Subcommand>),
+    Version,
+}
+
+/// The subcommands understood by grub-bootimage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subcommand {
+    Build,
+    Run,
+    Test,
+    Runner,
+    Clean,
+    Doctor,
+}
+
+/// Arguments shared by all subcommands.
+#[derive(Debug, Clone, Default)]
+pub struct Args {
+    /// The executable to use instead of building the kernel with cargo.
+    pub executable: Option<PathBuf>,
+    /// Path to the `Cargo.toml` of the kernel crate.
+    pub manifest_path: Option<PathBuf>,
+    /// The GRUB menu entry to boot, overriding `default-entry`.
+    pub entry: Option<String>,
+    /// Overrides `qemu-binary` from the configuration.
+    pub qemu_binary: Option<String>,
+    /// Overrides `test-timeout` from the configuration.
+    pub timeout: Option<u32>,
+    /// Suppress the status output of grub-bootimage.
+    pub quiet: bool,
+    /// Extra arguments passed to QEMU.
+    pub qemu_args: Vec<String>,
+    /// Arguments passed after the executable by cargo (e.g. test filters), which are appended
+    /// to the kernel command line.
+    pub runner_args: Vec<String>,
+    /// Arguments forwarded to `cargo build` (profile, target, features, package selection).
+    pub cargo_args: Vec<String>,
+}
+
+/// Flags forwarded to `cargo build` that take no value.
+const CARGO_FLAGS: &[&str] = &[
+    "--release",
+    "--all-features",
+    "--no-default-features",
+    "--workspace",
+    "--offline",
+    "--locked",
+    "--frozen",
+];
+
+/// Options forwarded to `cargo build` that take a value.
+const CARGO_OPTIONS: &[&str] = &[
+    "--profile",
+    "--target",
+    "--features",
+    "-F",
+    "--package",
+    "-p",
+    "--bin",
+    "--example",
+    "-Z",
+];
+
+pub fn parse_args() -> Result<Command> {
+    let mut raw_args = env::args().skip(1);
+
+    let subcommand = match raw_args.next().as_deref() {
+        Some("build") => Subcommand::Build,
+        Some("run") => Subcommand::Run,
+        Some("test") => Subcommand::Test,
+        Some("runner") => Subcommand::Runner,
+        Some("clean") => Subcommand::Clean,
+        Some("doctor") => Subcommand::Doctor,
+        Some("--help") | Some("-h") | Some("help") => return Ok(Command::Help(None)),
+        Some("--version") | Some("-V") => return Ok(Command::Version),
+        Some(any) => {
+            return Err(anyhow!(
+                "grub-bootimage: Unrecognized option '{}' (use --help for help)",
+                any
+            ))
+        }
+        None => {
+            return Err(anyhow!(
+                "grub-bootimage: No operation specified (use --help for help)"
+            ))
+        }
+    };
+
+    let mut args = Args::default();
+    while let Some(arg) = raw_args.next() {
+        // Everything after the executable of `runner` belongs to the kernel.
+        if subcommand == Subcommand::Runner && args.executable.is_some() {
+            args.runner_args.push(arg);
+            args.runner_args.extend(raw_args.by_ref());
+            break;
+        }
+        match arg.as_str() {
+            "--help" | "-h" => return Ok(Command::Help(Some(subcommand))),
+            "--quiet" | "-q" => args.quiet = true,
+            "--manifest-path" => {
+                let path = raw_args
+                    .next()
+                    .ok_or_else(|| anyhow!("`--manifest-path` requires a value"))?;
+                args.manifest_path = Some(PathBuf::from(path));
+            }
+            flag if accepts_cargo_args(subcommand) && CARGO_FLAGS.contains(&flag) => {
+                args.cargo_args.push(arg);
+            }
+            option if accepts_cargo_args(subcommand) && is_cargo_option(option) => {
+                args.cargo_args.push(arg.clone());
+                // The value is part of the argument for `--option=value` and `-Zvalue`.
+                if CARGO_OPTIONS.contains(&option) {
+                    let value = raw_args
+                        .next()
+                        .ok_or_else(|| anyhow!("`{}` requires a value", option))?;
+                    args.cargo_args.push(value);
+                }
+            }
+            "--entry" if !matches!(subcommand, Subcommand::Clean | Subcommand::Doctor) => {
+                let entry = raw_args
+                    .next()
+                    .ok_or_else(|| anyhow!("`--entry` requires a value"))?;
+                args.entry = Some(entry);
+            }
+            "--qemu-binary"
+                if matches!(
+                    subcommand,
+                    Subcommand::Run | Subcommand::Test | Subcommand::Runner
+                ) =>
+            {
+                let binary = raw_args
+                    .next()
+                    .ok_or_else(|| anyhow!("`--qemu-binary` requires a value"))?;
+                args.qemu_binary = Some(binary);
+            }
+            "--timeout" if matches!(subcommand, Subcommand::Test | Subcommand::Runner) => {
+                let timeout = raw_args
+                    .next()
+                    .ok_or_else(|| anyhow!("`--timeout` requires a value"))?;
+                args.timeout = Some(
+                    timeout
+                        .parse()
+                        .with_context(|| format!("invalid timeout `{}`", timeout))?,
+                );
+            }
+            "--" if matches!(
+                subcommand,
+                Subcommand::Run | Subcommand::Test | Subcommand::Runner
+            ) =>
+            {
+                args.qemu_args.extend(raw_args.by_ref());
+            }
+            flag if flag.starts_with('-') => {
+                return Err(anyhow!(
+                    "grub-bootimage: Unrecognized option '{}' (use --help for help)",
+                    flag
+                ))
+            }
+            exe if args.executable.is_none()
+                && !matches!(subcommand, Subcommand::Clean | Subcommand::Doctor) =>
+            {
+                args.executable = Some(PathBuf::from(exe));
+            }
+            any => return Err(anyhow!("grub-bootimage: Unexpected argument '{}'", any)),
+        }
+    }
+
+    if args.executable.is_some() && !args.cargo_args.is_empty() {
+        return Err(anyhow!(
+            "grub-bootimage: cargo options cannot be combined with an executable, \
+             which is used without building"
+        ));
+    }
+
+    Ok(match subcommand {
+        Subcommand::Build => Command::Build(args),
+        Subcommand::Run => Command::Run(args),
+        Subcommand::Test => Command::Test(args),
+        Subcommand::Runner => {
+            if args.executable.is_none() {
+                return Err(anyhow!("grub-bootimage: `runner` requires an executable"));
+            }
+            Command::Runner(args)
+        }
+        Subcommand::Clean => Command::Clean(args),
+        Subcommand::Doctor => Command::Doctor(args),
+    })
+}
+
+/// Returns whether the subcommand invokes `cargo build`.
+fn accepts_cargo_args(subcommand: Subcommand) -> bool {
+    !matches!(
+        subcommand,
+        Subcommand::Runner | Subcommand::Clean | Subcommand::Doctor
+    )
+}
+
+fn is_cargo_option(arg: &str) -> bool {
+    CARGO_OPTIONS
+        .iter()
+        .any(|option| match arg.strip_prefix(option) {
+            Some("") => true,
+            Some(rest) => rest.starts_with('=') || *option == "-Z",
+            None => false,
+        })
+}
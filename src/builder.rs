@@ -0,0 +1,737 @@
+use crate::{
+    config::{Config, PackageModule},
+    direct::{BootMethod, DirectBoot},
+    disk::{self, ImageFormat},
+    elf::{self, ElfClass},
+    firmware::{self, Firmware, Ovmf, Platform},
+    grub_cfg, modules,
+    multiboot::{self, MultibootHeader},
+    run::Mode,
+    staging::{self, StagingDir},
+    tools::{Tool, Tools},
+};
+use anyhow::{anyhow, Context, Result};
+use cargo_metadata::{diagnostic::DiagnosticLevel, Message, MetadataCommand, Target};
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+/// An executable produced by cargo.
+#[derive(Debug, Clone)]
+pub struct Executable {
+    pub path: PathBuf,
+    /// How the executable must be run, derived from cargo's artifact metadata.
+    pub mode: Mode,
+}
+
+/// A bootable image of a kernel.
+///
+/// The staging directory of the image is locked until this value is dropped, so the image
+/// must be kept alive while QEMU uses it.
+#[derive(Debug)]
+pub struct BootImage {
+    /// The image, or the staged kernel if QEMU loads it directly.
+    pub path: PathBuf,
+    pub format: ImageFormat,
+    /// The images converted with `qemu-img` from the image at `path`.
+    pub converted: Vec<PathBuf>,
+    /// The validated multiboot header the kernel is booted with.
+    pub multiboot: MultibootHeader,
+    /// The platforms the image is booted on in the mode it was created for.
+    pub platforms: Vec<Platform>,
+    /// The OVMF files, if the image is booted with UEFI.
+    ovmf: Option<Ovmf>,
+    /// The kernel and modules QEMU loads, if the kernel is booted without GRUB.
+    pub direct: Option<DirectBoot>,
+    tools: Tools,
+    staging: StagingDir,
+}
+
+impl BootImage {
+    /// Returns the QEMU arguments attaching the image as boot drive, or loading the kernel if
+    /// it is booted directly.
+    ///
+    /// If a raw image was converted to qcow2, a fresh copy-on-write overlay of the qcow2 image
+    /// is booted instead, so runs never modify the image.
+    pub fn boot_args(&self) -> Result<Vec<String>> {
+        if let Some(direct) = &self.direct {
+            return Ok(direct.qemu_args());
+        }
+        let qcow2 = self.converted.iter().find(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "qcow2")
+        });
+        let drive = match (self.format, qcow2) {
+            (ImageFormat::Iso, _) => {
+                return Ok(vec!["-cdrom".to_owned(), self.path.display().to_string()])
+            }
+            (ImageFormat::Raw, Some(qcow2)) => {
+                let overlay = self.staging.overlay_path();
+                disk::create_overlay(qcow2, &overlay, &self.tools)?;
+                format!("format=qcow2,file={}", overlay.display())
+            }
+            (ImageFormat::Raw, None) => format!("format=raw,file={}", self.path.display()),
+        };
+        Ok(vec!["-drive".to_owned(), drive])
+    }
+
+    /// Returns the QEMU system emulator matching the kernel on `platform`, used unless
+    /// `qemu-binary` names one.
+    ///
+    /// 32-bit x86 kernels run on `qemu-system-i386`, where accidental long mode instructions
+    /// fault. The ELF header alone cannot tell whether a kernel was converted to ELF32 and
+    /// switches to long mode, such kernels need `qemu-binary = "qemu-system-x86_64"`. OVMF
+    /// is a 64-bit firmware, so UEFI always needs `qemu-system-x86_64`.
+    pub fn qemu_system(&self, platform: &Platform) -> &'static str {
+        match (platform.firmware, self.multiboot.elf()) {
+            (Firmware::Bios, Some(elf))
+                if elf.class == ElfClass::Elf32 && elf.machine == elf::EM_386 =>
+            {
+                "qemu-system-i386"
+            }
+            _ => "qemu-system-x86_64",
+        }
+    }
+
+    /// Returns the QEMU arguments booting the image on `platform`.
+    ///
+    /// For UEFI a fresh copy of the OVMF variable store is created, so every run starts
+    /// with the same firmware settings.
+    pub fn platform_args(&self, platform: &Platform) -> Result<Vec<String>> {
+        let mut args = match (platform.firmware, &self.ovmf) {
+            (Firmware::Bios, _) => Vec::new(),
+            (Firmware::Uefi, Some(ovmf)) => ovmf.qemu_args(&self.staging.ovmf_vars_path())?,
+            (Firmware::Uefi, None) => {
+                return Err(anyhow!("the image was not created for UEFI"));
+            }
+        };
+        if let Some(machine) = &platform.machine {
+            args.push("-machine".to_owned());
+            args.push(machine.clone());
+        }
+        Ok(args)
+    }
+}
+
+/// The kinds of targets cargo builds test harnesses for.
+const HARNESS_KINDS: &[&str] = &["test", "bench", "example", "bin", "lib"];
+
+/// Builds the kernel with cargo and packs it into a bootable GRUB image.
+pub struct Builder {
+    manifest_path: PathBuf,
+    package_name: String,
+    target_dir: PathBuf,
+    /// The targets of all workspace packages.
+    targets: Vec<Target>,
+    tools: Tools,
+}
+
+impl Builder {
+    /// Creates a builder for the crate at `manifest_path`.
+    ///
+    /// If no manifest path is given, `CARGO_MANIFEST_DIR` is used when set (i.e. when
+    /// invoked by cargo), otherwise the nearest `Cargo.toml` above the current directory.
+    pub fn new(manifest_path: Option<PathBuf>) -> Result<Self> {
+        let manifest_path = match manifest_path {
+            Some(path) => path
+                .canonicalize()
+                .with_context(|| format!("Failed to find `{}`", path.display()))?,
+            None => locate_manifest()?,
+        };
+        // The `tools` table is not read yet, only the environment can select cargo here.
+        let tools = Tools::default();
+        let metadata = MetadataCommand::new()
+            .cargo_path(tools.path(Tool::Cargo))
+            .manifest_path(&manifest_path)
+            .no_deps()
+            .exec()
+            .context("Failed to run `cargo metadata`")?;
+        let package_name = metadata
+            .packages
+            .iter()
+            .find(|package| {
+                package.manifest_path.canonicalize().ok() == manifest_path.canonicalize().ok()
+            })
+            .map(|package| package.name.clone())
+            .ok_or_else(|| anyhow!("`{}` has no package", manifest_path.display()))?;
+        let target_dir = metadata.target_directory;
+        let targets = metadata
+            .packages
+            .into_iter()
+            .flat_map(|package| package.targets)
+            .collect();
+        Ok(Builder {
+            manifest_path,
+            package_name,
+            target_dir,
+            targets,
+            tools,
+        })
+    }
+
+    /// Runs the external tools from the paths of `tools`, the `tools` table of the
+    /// configuration.
+    pub fn set_tools(&mut self, tools: Tools) {
+        self.tools = tools;
+    }
+
+    /// Reads the `package.metadata.grub-bootimage` table of the manifest.
+    pub fn config(&self) -> Result<Config> {
+        crate::config::read_config(&self.manifest_path).context("Failed to read configuration")
+    }
+
+    /// Builds the kernel with cargo and returns all produced executables.
+    ///
+    /// If `tests` is set, the test executables are built instead of the normal ones.
+    /// `cargo_args` are forwarded to `cargo build` unchanged.
+    pub fn build_kernel(&self, tests: bool, cargo_args: &[String]) -> Result<Vec<Executable>> {
+        let mut args = Vec::new();
+        if tests {
+            args.push("--tests".to_owned());
+        }
+        args.extend(cargo_args.iter().cloned());
+        self.cargo_build(&args)
+    }
+
+    /// Determines how an executable built by cargo (e.g. passed to `runner`) must be run.
+    ///
+    /// cargo names the test harness builds of a target `<target>-<hash>`, wherever it places
+    /// them (`deps` for tests and benchmarks, `examples` for examples), while binaries and
+    /// examples are also copied to their plain name for running them. The target is looked up
+    /// in the workspace, so nothing needs to be built.
+    pub fn identify(&self, executable: &Path) -> Mode {
+        let stem = executable
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+        // cargo replaces dashes in the target name with underscores.
+        let target = stem
+            .rsplit_once('-')
+            .filter(|(_, hash)| hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|(name, _)| {
+                self.targets.iter().find(|target| {
+                    target.name.replace('-', "_") == name
+                        && target
+                            .kind
+                            .iter()
+                            .any(|kind| HARNESS_KINDS.contains(&kind.as_str()))
+                })
+            });
+        match target {
+            Some(target) if target.kind.iter().any(|kind| kind == "bench") => Mode::Bench,
+            Some(_) => Mode::Test,
+            // Executables in `deps` are test builds even if their target is not in the
+            // workspace, e.g. because of a custom layout.
+            None if executable
+                .parent()
+                .is_some_and(|parent| parent.ends_with("deps")) =>
+            {
+                Mode::Test
+            }
+            None => Mode::Run,
+        }
+    }
+
+    /// Builds the workspace package of a module and returns the path of its executable.
+    ///
+    /// A target specification ending in `.json` is relative to the directory of the manifest,
+    /// like the paths of other modules.
+    pub fn build_package(&self, module: &PackageModule) -> Result<PathBuf> {
+        let mut args = vec!["--package".to_owned(), module.package.clone()];
+        if let Some(bin) = &module.bin {
+            args.push("--bin".to_owned());
+            args.push(bin.clone());
+        }
+        if let Some(target) = &module.target {
+            let target = if target.ends_with(".json") {
+                self.manifest_dir().join(target).display().to_string()
+            } else {
+                target.clone()
+            };
+            args.push("--target".to_owned());
+            args.push(target);
+        }
+        if let Some(profile) = &module.profile {
+            args.push(format!("--profile={}", profile));
+        }
+
+        let executables = self
+            .cargo_build(&args)
+            .with_context(|| format!("Failed to build module package `{}`", module.package))?;
+        let mut paths: Vec<_> = executables
+            .into_iter()
+            .filter(|exe| exe.mode == Mode::Run)
+            .map(|exe| exe.path)
+            .collect();
+        match paths.len() {
+            1 => Ok(paths.remove(0)),
+            0 => Err(anyhow!(
+                "module package `{}` has no binary target",
+                module.package
+            )),
+            _ => Err(anyhow!(
+                "module package `{}` has several binaries, select one with `bin`",
+                module.package
+            )),
+        }
+    }
+
+    /// Runs `cargo build` with `args` and collects the executables from its messages.
+    ///
+    /// Compiler diagnostics are printed as they arrive.
+    fn cargo_build(&self, args: &[String]) -> Result<Vec<Executable>> {
+        let mut cmd = self.tools.command(Tool::Cargo);
+        cmd.arg("build");
+        cmd.arg("--manifest-path").arg(&self.manifest_path);
+        cmd.args(args);
+        cmd.arg("--message-format").arg("json");
+        cmd.stdout(Stdio::piped());
+        let mut child = cmd
+            .spawn()
+            .map_err(|err| self.tools.launch_error(Tool::Cargo, err))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("failed to capture the output of the kernel build"))?;
+
+        let mut executables = Vec::new();
+        let mut failed_targets = Vec::new();
+        for message in Message::parse_stream(BufReader::new(stdout)) {
+            match message.context("Failed to read cargo message")? {
+                Message::CompilerArtifact(artifact) => {
+                    if let Some(path) = artifact.executable {
+                        let mode = if artifact.target.kind.iter().any(|kind| kind == "bench") {
+                            Mode::Bench
+                        } else if artifact.profile.test {
+                            Mode::Test
+                        } else {
+                            Mode::Run
+                        };
+                        executables.push(Executable { path, mode });
+                    }
+                }
+                Message::CompilerMessage(message) => {
+                    if let Some(rendered) = &message.message.rendered {
+                        eprint!("{}", rendered);
+                    }
+                    if let DiagnosticLevel::Error | DiagnosticLevel::Ice = message.message.level {
+                        let target = describe_target(&message.target);
+                        if !failed_targets.contains(&target) {
+                            failed_targets.push(target);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let status = child
+            .wait()
+            .context("Failed to wait for the kernel build")?;
+        if !status.success() {
+            return Err(if failed_targets.is_empty() {
+                anyhow!("kernel build failed")
+            } else {
+                anyhow!(
+                    "kernel build failed: could not compile {}",
+                    failed_targets.join(", ")
+                )
+            });
+        }
+        Ok(executables)
+    }
+
+    /// Creates a bootable image containing `kernel` and the configured modules, passing the
+    /// kernel command line configured for `mode`.
+    ///
+    /// `entry` selects the menu entry that is booted without waiting for GRUB's menu, by
+    /// default the `default-entry` is booted after the `grub-timeout`.
+    ///
+    /// The image is created in the staging directory of `kernel`, which stays locked against
+    /// other grub-bootimage processes until the returned image is dropped.
+    pub fn create_image(
+        &self,
+        kernel: &Path,
+        config: &Config,
+        mode: Mode,
+        entry: Option<&str>,
+    ) -> Result<BootImage> {
+        let platforms = config.platforms(mode);
+        if config.boot_method == BootMethod::Direct {
+            return self.stage_direct_boot(kernel, config, mode, entry, platforms);
+        }
+        let multiboot = multiboot::read_header(kernel, config.protocol)?;
+        let mut firmware: Vec<Firmware> = Vec::new();
+        for platform in &platforms {
+            if !firmware.contains(&platform.firmware) {
+                firmware.push(platform.firmware);
+            }
+        }
+        // Plain BIOS ISOs are left to grub-mkrescue, which explains a missing platform.
+        let check_platforms =
+            config.image_format == ImageFormat::Raw || firmware != [Firmware::Bios];
+        for &firmware in firmware.iter().filter(|_| check_platforms) {
+            if firmware::grub_platform_dir(firmware.grub_platform(), &self.tools).is_none() {
+                return Err(anyhow!(
+                    "GRUB platform {} not found, {}",
+                    firmware.grub_platform(),
+                    firmware.grub_platform_hint()
+                ));
+            }
+        }
+        // grub-mkrescue puts all installed platforms into the ISO, which makes it bootable
+        // with both BIOS and UEFI. A UEFI-only image contains only the x86_64-efi platform.
+        let platform_dir = match firmware.as_slice() {
+            [Firmware::Uefi] => {
+                firmware::grub_platform_dir(Firmware::Uefi.grub_platform(), &self.tools)
+            }
+            _ => None,
+        };
+        let ovmf = if firmware.contains(&Firmware::Uefi) {
+            let path =
+                |path: &Option<String>| path.as_ref().map(|path| self.manifest_dir().join(path));
+            Some(Ovmf::locate(
+                path(&config.ovmf_code),
+                path(&config.ovmf_vars),
+            )?)
+        } else {
+            None
+        };
+        let staging = StagingDir::acquire(&self.staging_root(), kernel)?;
+        let sysroot = staging.sysroot();
+        let image_out = staging.image_path(config.image_format.extension());
+        stage_kernel(kernel, &sysroot)?;
+        let grub_out = sysroot.join("boot/grub");
+        let grub_cfg = grub_out.join("grub.cfg");
+        fs::create_dir_all(&grub_out)?;
+
+        let (entries, default) = self.menu_entries(config, mode, entry)?;
+        let mut modules = Vec::new();
+        for entry in &entries {
+            modules::merge(&mut modules, &entry.modules)?;
+        }
+        for module in &modules {
+            module.install(&sysroot)?;
+        }
+
+        // Build grub config
+        // Without a selected entry, GRUB waits for a choice between several entries when run
+        // interactively.
+        let timeout = match (mode, entry, config.grub_timeout) {
+            (Mode::Run, None, Some(timeout)) => timeout as i32,
+            (Mode::Run, None, None) if entries.len() > 1 => -1,
+            _ => 0,
+        };
+        let vars = grub_cfg::Variables {
+            package_name: &self.package_name,
+            entries: &entries,
+            default,
+            timeout,
+            mode,
+            serial: config.grub_serial,
+            protocol: multiboot.protocol(),
+        };
+        let grub_config = match &config.grub_cfg_template {
+            Some(template) => {
+                grub_cfg::render_template(&self.manifest_dir().join(template), &vars)?
+            }
+            None => grub_cfg::default_config(&vars),
+        };
+        fs::write(grub_cfg, grub_config)?;
+
+        match config.image_format {
+            ImageFormat::Iso => {
+                grub_mkrescue(&image_out, &sysroot, platform_dir.as_deref(), &self.tools)?
+            }
+            ImageFormat::Raw => disk::create_disk_image(
+                &image_out,
+                &sysroot,
+                staging.path(),
+                config.partition_table,
+                config.filesystem,
+                &firmware,
+                &self.tools,
+            )?,
+        }
+        let mut converted = Vec::new();
+        for format in config.convert_to.iter().flatten() {
+            converted.push(disk::convert_image(&image_out, format, &self.tools)?);
+        }
+
+        Ok(BootImage {
+            path: image_out,
+            format: config.image_format,
+            converted,
+            multiboot,
+            platforms,
+            ovmf,
+            direct: None,
+            tools: self.tools.clone(),
+            staging,
+        })
+    }
+
+    /// Stages `kernel` and the modules of the booted menu entry for QEMU's multiboot loader,
+    /// which boots them without GRUB or an image.
+    fn stage_direct_boot(
+        &self,
+        kernel: &Path,
+        config: &Config,
+        mode: Mode,
+        entry: Option<&str>,
+        platforms: Vec<Platform>,
+    ) -> Result<BootImage> {
+        let multiboot = multiboot::read_multiboot1_header(kernel)?;
+        if let Some(platform) = platforms
+            .iter()
+            .find(|platform| platform.firmware != Firmware::Bios)
+        {
+            return Err(anyhow!(
+                "QEMU can only load the kernel directly with bios firmware, not {}",
+                platform.firmware
+            ));
+        }
+        let staging = StagingDir::acquire(&self.staging_root(), kernel)?;
+        let sysroot = staging.sysroot();
+        let kernel_out = stage_kernel(kernel, &sysroot)?;
+
+        // Without GRUB's menu, the selected or default entry is booted.
+        let (entries, default) = self.menu_entries(config, mode, entry)?;
+        let entry = &entries[default];
+        for module in &entry.modules {
+            module.install(&sysroot)?;
+        }
+        let direct = DirectBoot::new(&kernel_out, &multiboot, entry, &sysroot)?;
+
+        Ok(BootImage {
+            path: kernel_out,
+            format: config.image_format,
+            converted: Vec::new(),
+            multiboot: MultibootHeader::V1(multiboot),
+            platforms,
+            ovmf: None,
+            direct: Some(direct),
+            tools: self.tools.clone(),
+            staging,
+        })
+    }
+
+    /// Resolves the menu entries of the configuration and returns them with the index of the
+    /// entry to boot.
+    fn menu_entries(
+        &self,
+        config: &Config,
+        mode: Mode,
+        selected: Option<&str>,
+    ) -> Result<(Vec<grub_cfg::Entry>, usize)> {
+        let cmdline = config.kernel_cmdline(mode);
+        let modules = match &config.modules {
+            Some(modules) => modules::resolve(modules, self)?,
+            None => Vec::new(),
+        };
+        let menu_entries = match &config.menu_entries {
+            Some(menu_entries) => menu_entries,
+            None => {
+                if let Some(name) = selected.filter(|&name| name != self.package_name) {
+                    return Err(anyhow!(
+                        "menu entry `{}` not found, no `menu-entries` are configured",
+                        name
+                    ));
+                }
+                let entry = grub_cfg::Entry {
+                    name: self.package_name.clone(),
+                    cmdline,
+                    modules,
+                };
+                return Ok((vec![entry], 0));
+            }
+        };
+
+        let mut entries = Vec::new();
+        for menu_entry in menu_entries {
+            let mut entry_modules = modules.clone();
+            if let Some(extra) = &menu_entry.modules {
+                modules::merge(&mut entry_modules, &modules::resolve(extra, self)?)?;
+            }
+            let cmdline = match menu_entry.kernel_args.as_deref().map(str::trim) {
+                Some(args) if !args.is_empty() && !cmdline.is_empty() => {
+                    format!("{} {}", cmdline, args)
+                }
+                Some(args) if !args.is_empty() => args.to_owned(),
+                _ => cmdline.clone(),
+            };
+            entries.push(grub_cfg::Entry {
+                name: menu_entry.name.clone(),
+                cmdline,
+                modules: entry_modules,
+            });
+        }
+
+        let default = match selected.or(config.default_entry.as_deref()) {
+            Some(name) => entries
+                .iter()
+                .position(|entry| entry.name == name)
+                .ok_or_else(|| {
+                    let names: Vec<_> = entries
+                        .iter()
+                        .map(|entry| format!("`{}`", entry.name))
+                        .collect();
+                    anyhow!(
+                        "menu entry `{}` not found, available entries: {}",
+                        name,
+                        names.join(", ")
+                    )
+                })?,
+            None => 0,
+        };
+        Ok((entries, default))
+    }
+
+    /// Removes all images created by grub-bootimage from the target directory.
+    ///
+    /// Returns the paths that were removed and the staging directories that were skipped
+    /// because another grub-bootimage process is using them.
+    pub fn clean(&self) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+        let (mut removed, busy) = staging::remove_all(&self.staging_root())?;
+
+        // Images created by versions that used a fixed location.
+        let sysroot = self.target_dir.join("sysroot");
+        if sysroot.exists() {
+            fs::remove_dir_all(&sysroot)
+                .with_context(|| format!("Failed to remove `{}`", sysroot.display()))?;
+            removed.push(sysroot);
+        }
+        let iso = self.target_dir.join("os.iso");
+        if iso.exists() {
+            fs::remove_file(&iso)
+                .with_context(|| format!("Failed to remove `{}`", iso.display()))?;
+            removed.push(iso);
+        }
+        Ok((removed, busy))
+    }
+
+    /// The directory of the manifest, which paths in the configuration are relative to.
+    pub fn manifest_dir(&self) -> &Path {
+        self.manifest_path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+    }
+
+    fn staging_root(&self) -> PathBuf {
+        self.target_dir.join("grub-bootimage")
+    }
+}
+
+/// Empties `sysroot` and copies `kernel` into it, returns the path of the copy.
+fn stage_kernel(kernel: &Path, sysroot: &Path) -> Result<PathBuf> {
+    if sysroot.exists() {
+        fs::remove_dir_all(sysroot)
+            .with_context(|| format!("Failed to remove `{}`", sysroot.display()))?;
+    }
+    let kernel_out = sysroot.join(grub_cfg::KERNEL_PATH.trim_start_matches('/'));
+    if let Some(parent) = kernel_out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(kernel, &kernel_out)
+        .with_context(|| format!("Failed to copy kernel `{}`", kernel.display()))?;
+    Ok(kernel_out)
+}
+
+/// Runs `grub-mkrescue` to turn `sysroot` into the ISO at `iso`.
+///
+/// The ISO contains all installed GRUB platforms unless `platform_dir` selects one.
+fn grub_mkrescue(
+    iso: &Path,
+    sysroot: &Path,
+    platform_dir: Option<&Path>,
+    tools: &Tools,
+) -> Result<()> {
+    if iso.exists() {
+        fs::remove_file(iso).with_context(|| format!("Failed to remove `{}`", iso.display()))?;
+    }
+    let mut cmd = tools.command(Tool::GrubMkrescue);
+    if let Some(dir) = platform_dir {
+        cmd.arg("-d").arg(dir);
+    }
+    if let Some(xorriso) = tools.configured(Tool::Xorriso) {
+        let mut arg = OsString::from("--xorriso=");
+        arg.push(xorriso);
+        cmd.arg(arg);
+    }
+    let output = cmd
+        .arg("-o")
+        .arg(iso)
+        .arg(sysroot)
+        .output()
+        .map_err(|err| tools.launch_error(Tool::GrubMkrescue, err))?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        let stderr = stderr.trim();
+        return Err(match explain_mkrescue_error(stderr, tools) {
+            Some(hint) => anyhow!("grub-mkrescue failed: {}\n\n{}", hint, stderr),
+            None => anyhow!("grub-mkrescue failed ({}):\n\n{}", output.status, stderr),
+        });
+    }
+    // Some versions exit successfully even though xorriso could not write the image.
+    if !iso.exists() {
+        return Err(anyhow!(
+            "grub-mkrescue did not create `{}`:\n\n{}",
+            iso.display(),
+            stderr.trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Maps the well-known failure modes of grub-mkrescue to actionable messages.
+fn explain_mkrescue_error(stderr: &str, tools: &Tools) -> Option<String> {
+    let missing = |tool: Tool| {
+        format!(
+            "{} (`{}`) is missing, {}",
+            tool.name(),
+            tools.path(tool).display(),
+            tool.hint()
+        )
+    };
+    if stderr.contains("xorriso not found") || stderr.contains("`xorriso' invocation failed") {
+        Some(missing(Tool::Xorriso))
+    } else if stderr.contains("mformat") {
+        Some(missing(Tool::Mformat))
+    } else if stderr.contains("doesn't exist. Please specify --target or --directory") {
+        Some(
+            "no GRUB platform directory was found, install the GRUB platform files \
+             (e.g. the `grub-pc-bin` package for `i386-pc`)"
+                .to_owned(),
+        )
+    } else {
+        None
+    }
+}
+
+/// Describes a cargo target for error messages, e.g. ``bin `kernel` ``.
+fn describe_target(target: &Target) -> String {
+    let kind = target.kind.first().map(String::as_str).unwrap_or("target");
+    format!("{} `{}`", kind, target.name)
+}
+
+fn locate_manifest() -> Result<PathBuf> {
+    if let Ok(manifest_dir) = env::var("CARGO_MANIFEST_DIR") {
+        return Ok(Path::new(&manifest_dir).join("Cargo.toml"));
+    }
+    let cwd = env::current_dir().context("Cannot access current directory")?;
+    cwd.ancestors()
+        .map(|dir| dir.join("Cargo.toml"))
+        .find(|manifest| manifest.exists())
+        .ok_or_else(|| {
+            anyhow!(
+                "Could not find `Cargo.toml` in `{}` or any parent directory",
+                cwd.display()
+            )
+        })
+}
@@ -1,5 +1,14 @@
+use crate::{
+    archive::ArchiveFormat,
+    direct::BootMethod,
+    disk::{self, Filesystem, ImageFormat, PartitionTable},
+    firmware::{Firmware, Platform},
+    multiboot::Protocol,
+    run::Mode,
+    tools::{Tool, Tools},
+};
 use anyhow::{anyhow, Context, Result};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use toml::Value;
 
 /// The configuration table `package.metadata.grub-bootimage`.
@@ -7,7 +16,7 @@ use toml::Value;
 #[non_exhaustive]
 pub struct Config {
     /// Modules to load with the kernel.
-    pub modules: Option<Vec<String>>,
+    pub modules: Option<Vec<Module>>,
     /// Extra arguments passed to QEMU in not testing mode.
     pub run_args: Option<Vec<String>>,
     /// Extra arguments passed to QEMU in testing mode.
@@ -16,16 +25,234 @@ pub struct Config {
     pub test_success_exit_code: Option<i32>,
     /// The amount of time to wait before giving up on QEMU.
     pub test_timeout: u32,
+    /// Extra arguments passed to QEMU in benchmark mode.
+    pub bench_args: Option<Vec<String>>,
+    /// The amount of time to wait before giving up on QEMU in benchmark mode.
+    pub bench_timeout: Option<u32>,
+    /// The kernel command line, passed in every mode.
+    pub kernel_args: Option<String>,
+    /// Appended to the kernel command line in not testing mode.
+    pub run_kernel_args: Option<String>,
+    /// Appended to the kernel command line in testing and benchmark mode.
+    pub test_kernel_args: Option<String>,
+    /// Path of a template for the `grub.cfg`, relative to the manifest.
+    pub grub_cfg_template: Option<String>,
+    /// The entries of the GRUB menu, a single entry named after the package if `None`.
+    pub menu_entries: Option<Vec<MenuEntry>>,
+    /// Seconds GRUB shows the menu before booting the default entry.
+    pub grub_timeout: Option<u32>,
+    /// Name of the menu entry booted by default, the first entry if `None`.
+    pub default_entry: Option<String>,
+    /// Makes GRUB use the serial port as its console, so its errors show up in headless runs.
+    pub grub_serial: Option<SerialConsole>,
+    /// The firmware the image is built for and booted with.
+    pub firmware: Firmware,
+    /// Path of the OVMF code file, relative to the manifest, searched for if `None`.
+    pub ovmf_code: Option<String>,
+    /// Path of the OVMF variable store, relative to the manifest, searched for if `None`.
+    pub ovmf_vars: Option<String>,
+    /// The firmware every test is run with, only `firmware` if `None`.
+    pub test_firmware: Option<Vec<Firmware>>,
+    /// The QEMU machine types every test is run on, QEMU's default if `None`.
+    pub test_machines: Option<Vec<String>>,
+    /// Whether an ISO or a raw disk image is created.
+    pub image_format: ImageFormat,
+    /// The partition table of raw disk images.
+    pub partition_table: PartitionTable,
+    /// The file system of the boot partition of raw disk images.
+    pub filesystem: Filesystem,
+    /// Formats the image is converted to with `qemu-img` after it has been created.
+    pub convert_to: Option<Vec<String>>,
+    /// Whether the kernel is booted by GRUB or loaded by QEMU directly.
+    pub boot_method: BootMethod,
+    /// The protocol GRUB boots the kernel with, detected from its headers if `None`.
+    pub protocol: Option<Protocol>,
+    /// The QEMU system emulator, chosen from the kernel's ELF header if `None`.
+    pub qemu_binary: Option<String>,
+    /// Paths of the external tools, found in `PATH` if not configured.
+    pub tools: Tools,
+    /// The `isa-debug-exit` device added in testing and benchmark mode.
+    pub test_exit_device: Option<ExitDevice>,
+}
+
+/// The serial port GRUB uses as console, the `grub-serial` key.
+///
+/// `grub-serial = true` uses the first port with 115200 baud, a table like
+/// `{ unit = 1, speed = 9600 }` selects another port or speed.
+#[derive(Debug, Clone, Copy)]
+pub struct SerialConsole {
+    pub unit: u32,
+    pub speed: u32,
+}
+
+/// The `isa-debug-exit` device the kernel reports its test result with, the
+/// `test-exit-device` key.
+///
+/// A guest write of `value` to the device makes QEMU exit with `(value << 1) | 1`, which is
+/// translated back to `value`. `test-exit-device = true` uses the port `0xf4` and the values
+/// `0x10` for success and `0x11` for failure, a table like
+/// `{ iobase = 0x501, iosize = 2, success = 1, failure = 2 }` changes them.
+#[derive(Debug, Clone, Copy)]
+pub struct ExitDevice {
+    pub iobase: u16,
+    pub iosize: u8,
+    /// The value the kernel writes when all tests passed.
+    pub success: u8,
+    /// The value the kernel writes when a test failed.
+    pub failure: u8,
+}
+
+/// A module loaded by GRUB together with the kernel, an entry of `modules`.
+///
+/// Entries are either a path or a table like
+/// `{ path = "initrd.tar", args = "root", dest = "/boot/initrd" }`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Module {
+    /// Where the contents of the module come from.
+    pub source: ModuleSource,
+    /// The command line string of the module.
+    pub args: Option<String>,
+    /// Absolute path of the module in the image, defaults to `/<file name>`.
+    pub dest: Option<String>,
+}
+
+/// An entry of the GRUB menu, an entry of `menu-entries`.
+///
+/// Entries boot the same kernel with their own command line and modules, e.g.
+/// `{ name = "safe mode", kernel-args = "nosmp", modules = ["safe.cfg"] }`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct MenuEntry {
+    pub name: String,
+    /// Appended to the kernel command line of the mode.
+    pub kernel_args: Option<String>,
+    /// Loaded in addition to the `modules` of the configuration.
+    pub modules: Option<Vec<Module>>,
+}
+
+/// The contents of a module.
+#[derive(Debug, Clone)]
+pub enum ModuleSource {
+    /// A file or glob pattern, the `path` key.
+    Path(String),
+    /// An archive of a directory, the `dir` and `format` keys.
+    Archive { dir: String, format: ArchiveFormat },
+    /// An executable of a workspace package built with cargo, the `package` key.
+    Package(PackageModule),
+}
+
+/// A workspace package built by grub-bootimage to be loaded as a module.
+#[derive(Debug, Clone)]
+pub struct PackageModule {
+    /// Name of the package, as passed to `cargo build --package`.
+    pub package: String,
+    /// The binary target to build, required if the package has several.
+    pub bin: Option<String>,
+    /// The target triple or target specification the package is built for.
+    pub target: Option<String>,
+    /// The cargo profile the package is built with.
+    pub profile: Option<String>,
 }
 
 impl Config {
-    fn new() -> Config {
+    /// Returns the kernel command line for `mode`.
+    pub fn kernel_cmdline(&self, mode: Mode) -> String {
+        let mode_args = match mode {
+            Mode::Run => &self.run_kernel_args,
+            Mode::Test | Mode::Bench => &self.test_kernel_args,
+        };
+        let args: Vec<&str> = [&self.kernel_args, mode_args]
+            .iter()
+            .filter_map(|args| args.as_deref())
+            .map(str::trim)
+            .filter(|args| !args.is_empty())
+            .collect();
+        args.join(" ")
+    }
+
+    /// Appends `args` to the kernel command line of `mode`, separated by spaces.
+    pub fn append_kernel_args(&mut self, mode: Mode, args: &[String]) {
+        if args.is_empty() {
+            return;
+        }
+        let mode_args = match mode {
+            Mode::Run => &mut self.run_kernel_args,
+            Mode::Test | Mode::Bench => &mut self.test_kernel_args,
+        };
+        let mut cmdline = mode_args.take().unwrap_or_default();
+        for arg in args {
+            if !cmdline.trim().is_empty() {
+                cmdline.push(' ');
+            }
+            cmdline.push_str(arg);
+        }
+        *mode_args = Some(cmdline);
+    }
+
+    /// Returns the platforms the kernel is booted on in `mode`.
+    ///
+    /// Tests run on every combination of `test-firmware` and `test-machines`, everything
+    /// else on `firmware` and QEMU's default machine.
+    pub fn platforms(&self, mode: Mode) -> Vec<Platform> {
+        let default_firmware = [self.firmware];
+        let (firmware, machines) = match mode {
+            Mode::Test => (
+                self.test_firmware.as_deref().unwrap_or(&default_firmware),
+                self.test_machines.as_deref(),
+            ),
+            Mode::Run | Mode::Bench => (&default_firmware[..], None),
+        };
+        let machines: Vec<Option<String>> = match machines {
+            Some(machines) => machines.iter().cloned().map(Some).collect(),
+            None => vec![None],
+        };
+        let mut platforms = Vec::new();
+        for &firmware in firmware {
+            for machine in &machines {
+                let platform = Platform {
+                    firmware,
+                    machine: machine.clone(),
+                };
+                if !platforms.contains(&platform) {
+                    platforms.push(platform);
+                }
+            }
+        }
+        platforms
+    }
+
+    pub fn new() -> Config {
         Config {
             modules: None,
             run_args: None,
             test_args: None,
             test_success_exit_code: None,
             test_timeout: 300,
+            bench_args: None,
+            bench_timeout: None,
+            kernel_args: None,
+            run_kernel_args: None,
+            test_kernel_args: None,
+            grub_cfg_template: None,
+            menu_entries: None,
+            grub_timeout: None,
+            default_entry: None,
+            grub_serial: None,
+            firmware: Firmware::Bios,
+            ovmf_code: None,
+            ovmf_vars: None,
+            test_firmware: None,
+            test_machines: None,
+            image_format: ImageFormat::Iso,
+            partition_table: PartitionTable::Mbr,
+            filesystem: Filesystem::Fat,
+            convert_to: None,
+            boot_method: BootMethod::Grub,
+            protocol: None,
+            qemu_binary: None,
+            tools: Tools::default(),
+            test_exit_device: None,
         }
     }
 }
@@ -61,7 +288,7 @@ pub fn read_config(cargo_toml: &PathBuf) -> Result<Config> {
     for (key, value) in metadata {
         match (key.as_str(), value.clone()) {
             ("modules", Value::Array(array)) => {
-                config.modules = Some(parse_config(array)?);
+                config.modules = Some(parse_modules(array)?);
             }
             ("run-args", Value::Array(array)) => {
                 config.run_args = Some(parse_config(array)?);
@@ -72,6 +299,102 @@ pub fn read_config(cargo_toml: &PathBuf) -> Result<Config> {
             ("test-timeout", Value::Integer(timeout)) => {
                 config.test_timeout = timeout as u32;
             }
+            ("bench-args", Value::Array(array)) => {
+                config.bench_args = Some(parse_config(array)?);
+            }
+            ("bench-timeout", Value::Integer(timeout)) => {
+                config.bench_timeout = Some(timeout as u32);
+            }
+            ("kernel-args", Value::String(args)) => {
+                config.kernel_args = Some(args);
+            }
+            ("run-kernel-args", Value::String(args)) => {
+                config.run_kernel_args = Some(args);
+            }
+            ("test-kernel-args", Value::String(args)) => {
+                config.test_kernel_args = Some(args);
+            }
+            ("grub-cfg-template", Value::String(path)) => {
+                config.grub_cfg_template = Some(path);
+            }
+            ("menu-entries", Value::Array(array)) => {
+                config.menu_entries = Some(parse_menu_entries(array)?);
+            }
+            ("grub-timeout", Value::Integer(timeout)) => {
+                config.grub_timeout = Some(timeout as u32);
+            }
+            ("default-entry", Value::String(name)) => {
+                config.default_entry = Some(name);
+            }
+            ("grub-serial", value) => {
+                config.grub_serial = parse_serial(value)?;
+            }
+            ("firmware", Value::String(name)) => {
+                config.firmware = Firmware::from_name(&name)?;
+            }
+            ("ovmf-code", Value::String(path)) => {
+                config.ovmf_code = Some(path);
+            }
+            ("ovmf-vars", Value::String(path)) => {
+                config.ovmf_vars = Some(path);
+            }
+            ("test-firmware", Value::Array(array)) => {
+                let names = parse_config(array)?;
+                let firmware = names
+                    .iter()
+                    .map(|name| Firmware::from_name(name))
+                    .collect::<Result<Vec<_>>>()?;
+                if firmware.is_empty() {
+                    return Err(anyhow!("`test-firmware` must not be empty"));
+                }
+                config.test_firmware = Some(firmware);
+            }
+            ("test-machines", Value::Array(array)) => {
+                let machines = parse_config(array)?;
+                if machines.is_empty() {
+                    return Err(anyhow!("`test-machines` must not be empty"));
+                }
+                config.test_machines = Some(machines);
+            }
+            ("image-format", Value::String(name)) => {
+                config.image_format = ImageFormat::from_name(&name)?;
+            }
+            ("partition-table", Value::String(name)) => {
+                config.partition_table = PartitionTable::from_name(&name)?;
+            }
+            ("filesystem", Value::String(name)) => {
+                config.filesystem = Filesystem::from_name(&name)?;
+            }
+            ("convert-to", Value::Array(array)) => {
+                let formats = parse_config(array)?;
+                if let Some(format) = formats
+                    .iter()
+                    .find(|format| !disk::CONVERSION_FORMATS.contains(&format.as_str()))
+                {
+                    return Err(anyhow!(
+                        "grub-bootimage: cannot convert to `{}` (expected one of {})",
+                        format,
+                        disk::CONVERSION_FORMATS.join(", ")
+                    ));
+                }
+                config.convert_to = Some(formats);
+            }
+            ("boot-method", Value::String(name)) => {
+                config.boot_method = BootMethod::from_name(&name)?;
+            }
+            ("protocol", Value::String(name)) => {
+                config.protocol = Some(Protocol::from_name(&name)?);
+            }
+            ("qemu-binary", Value::String(binary)) => {
+                config.qemu_binary = Some(binary);
+            }
+            ("tools", Value::Table(table)) => {
+                let manifest_dir = cargo_toml.parent().unwrap_or_else(|| Path::new("."));
+                config.tools = parse_tools(table, manifest_dir)?;
+            }
+            ("test-exit-device", value) => {
+                config.test_exit_device = parse_exit_device(value)?;
+            }
             ("test-success-exit-code", Value::Integer(exit_code)) => {
                 config.test_success_exit_code = Some(exit_code as i32);
             }
@@ -84,6 +407,26 @@ pub fn read_config(cargo_toml: &PathBuf) -> Result<Config> {
             }
         }
     }
+    if let (Some(name), Some(entries)) = (&config.default_entry, &config.menu_entries) {
+        if !entries.iter().any(|entry| &entry.name == name) {
+            return Err(anyhow!(
+                "grub-bootimage: `default-entry` `{}` is not in `menu-entries`",
+                name
+            ));
+        }
+    }
+    if config.test_exit_device.is_some() && config.test_success_exit_code.is_some() {
+        return Err(anyhow!(
+            "grub-bootimage: `test-success-exit-code` cannot be combined with \
+             `test-exit-device`, set its `success` value instead"
+        ));
+    }
+    if config.boot_method == BootMethod::Direct && config.protocol == Some(Protocol::Multiboot2) {
+        return Err(anyhow!(
+            "grub-bootimage: `boot-method = \"direct\"` requires the multiboot protocol, QEMU \
+             cannot load multiboot2 kernels"
+        ));
+    }
     Ok(config)
 }
 
@@ -97,3 +440,241 @@ fn parse_config(array: Vec<Value>) -> Result<Vec<String>> {
     }
     Ok(parsed)
 }
+
+fn parse_modules(array: Vec<Value>) -> Result<Vec<Module>> {
+    let mut modules = Vec::new();
+    for val in array {
+        let module = match val {
+            Value::String(path) => Module {
+                source: ModuleSource::Path(path),
+                args: None,
+                dest: None,
+            },
+            Value::Table(table) => {
+                let mut path = None;
+                let mut dir = None;
+                let mut format = None;
+                let mut package = None;
+                let mut bin = None;
+                let mut target = None;
+                let mut profile = None;
+                let mut module_args = None;
+                let mut dest = None;
+                for (key, value) in table {
+                    match (key.as_str(), value) {
+                        ("path", Value::String(s)) => path = Some(s),
+                        ("dir", Value::String(s)) => dir = Some(s),
+                        ("format", Value::String(s)) => {
+                            format = Some(ArchiveFormat::from_name(&s)?)
+                        }
+                        ("package", Value::String(s)) => package = Some(s),
+                        ("bin", Value::String(s)) => bin = Some(s),
+                        ("target", Value::String(s)) => target = Some(s),
+                        ("profile", Value::String(s)) => profile = Some(s),
+                        ("args", Value::String(s)) => module_args = Some(s),
+                        ("dest", Value::String(s)) => dest = Some(s),
+                        (key, value) => {
+                            return Err(anyhow!(
+                                "grub-bootimage: unexpected module key `{}` with value `{}`",
+                                key,
+                                value
+                            ))
+                        }
+                    }
+                }
+                let package_keys = bin.is_some() || target.is_some() || profile.is_some();
+                let source = match (path, dir, package) {
+                    (Some(path), None, None) if format.is_none() && !package_keys => {
+                        ModuleSource::Path(path)
+                    }
+                    (None, Some(dir), None) if !package_keys => ModuleSource::Archive {
+                        dir,
+                        format: format.unwrap_or(ArchiveFormat::Ustar),
+                    },
+                    (None, None, Some(package)) if format.is_none() => {
+                        ModuleSource::Package(PackageModule {
+                            package,
+                            bin,
+                            target,
+                            profile,
+                        })
+                    }
+                    _ => {
+                        return Err(anyhow!(
+                            "module entries must have either a `path`, a `dir` and an \
+                             optional archive `format`, or a `package` and optional `bin`, \
+                             `target` and `profile`"
+                        ))
+                    }
+                };
+                Module {
+                    source,
+                    args: module_args,
+                    dest,
+                }
+            }
+            _ => return Err(anyhow!("modules must be paths or tables")),
+        };
+        modules.push(module);
+    }
+    Ok(modules)
+}
+
+fn parse_serial(value: Value) -> Result<Option<SerialConsole>> {
+    let mut serial = SerialConsole {
+        unit: 0,
+        speed: 115_200,
+    };
+    match value {
+        Value::Boolean(false) => return Ok(None),
+        Value::Boolean(true) => {}
+        Value::Table(table) => {
+            for (key, value) in table {
+                match (key.as_str(), value) {
+                    ("unit", Value::Integer(unit)) => serial.unit = unit as u32,
+                    ("speed", Value::Integer(speed)) => serial.speed = speed as u32,
+                    (key, value) => {
+                        return Err(anyhow!(
+                            "grub-bootimage: unexpected grub-serial key `{}` with value `{}`",
+                            key,
+                            value
+                        ))
+                    }
+                }
+            }
+        }
+        value => {
+            return Err(anyhow!(
+                "grub-bootimage: `grub-serial` must be a boolean or a table, found `{}`",
+                value
+            ))
+        }
+    }
+    Ok(Some(serial))
+}
+
+/// Parses the `tools` table, paths containing a `/` are relative to `manifest_dir`.
+fn parse_tools(table: toml::value::Table, manifest_dir: &Path) -> Result<Tools> {
+    let mut tools = Tools::default();
+    for (key, value) in table {
+        let tool = Tool::from_name(&key).context("grub-bootimage: invalid `tools` table")?;
+        let path = match value {
+            Value::String(path) => PathBuf::from(path),
+            value => {
+                return Err(anyhow!(
+                    "grub-bootimage: the path of tool `{}` must be a string, found `{}`",
+                    key,
+                    value
+                ))
+            }
+        };
+        let path = if path.is_relative() && path.components().count() > 1 {
+            manifest_dir.join(path)
+        } else {
+            path
+        };
+        tools.set(tool, path);
+    }
+    Ok(tools)
+}
+
+fn parse_exit_device(value: Value) -> Result<Option<ExitDevice>> {
+    let mut device = ExitDevice {
+        iobase: 0xf4,
+        iosize: 4,
+        success: 0x10,
+        failure: 0x11,
+    };
+    match value {
+        Value::Boolean(false) => return Ok(None),
+        Value::Boolean(true) => {}
+        Value::Table(table) => {
+            for (key, value) in table {
+                // QEMU's exit status has 8 bits, so only 7 bits of a value survive the shift.
+                let (max, integer) = match (key.as_str(), &value) {
+                    ("iobase", Value::Integer(integer)) => (0xffff, *integer),
+                    ("iosize", Value::Integer(integer)) => (4, *integer),
+                    ("success" | "failure", Value::Integer(integer)) => (0x7f, *integer),
+                    (key, value) => {
+                        return Err(anyhow!(
+                            "grub-bootimage: unexpected test-exit-device key `{}` with value `{}`",
+                            key,
+                            value
+                        ))
+                    }
+                };
+                if !(0..=max).contains(&integer) {
+                    return Err(anyhow!(
+                        "grub-bootimage: test-exit-device `{}` must be at most {:#x}, found {:#x}",
+                        key,
+                        max,
+                        integer
+                    ));
+                }
+                match key.as_str() {
+                    "iobase" => device.iobase = integer as u16,
+                    "iosize" => device.iosize = integer as u8,
+                    "success" => device.success = integer as u8,
+                    _ => device.failure = integer as u8,
+                }
+            }
+        }
+        value => {
+            return Err(anyhow!(
+                "grub-bootimage: `test-exit-device` must be a boolean or a table, found `{}`",
+                value
+            ))
+        }
+    }
+    if ![1, 2, 4].contains(&device.iosize) {
+        return Err(anyhow!(
+            "grub-bootimage: test-exit-device `iosize` must be 1, 2 or 4"
+        ));
+    }
+    if device.success == device.failure {
+        return Err(anyhow!(
+            "grub-bootimage: test-exit-device `success` and `failure` must differ"
+        ));
+    }
+    Ok(Some(device))
+}
+
+fn parse_menu_entries(array: Vec<Value>) -> Result<Vec<MenuEntry>> {
+    let mut entries: Vec<MenuEntry> = Vec::new();
+    for val in array {
+        let table = match val {
+            Value::Table(table) => table,
+            _ => return Err(anyhow!("menu entries must be tables")),
+        };
+        let mut name = None;
+        let mut kernel_args = None;
+        let mut modules = None;
+        for (key, value) in table {
+            match (key.as_str(), value) {
+                ("name", Value::String(s)) => name = Some(s),
+                ("kernel-args", Value::String(s)) => kernel_args = Some(s),
+                ("modules", Value::Array(array)) => modules = Some(parse_modules(array)?),
+                (key, value) => {
+                    return Err(anyhow!(
+                        "grub-bootimage: unexpected menu entry key `{}` with value `{}`",
+                        key,
+                        value
+                    ))
+                }
+            }
+        }
+        let name = name.ok_or_else(|| anyhow!("menu entries must have a `name`"))?;
+        if entries.iter().any(|entry| entry.name == name) {
+            return Err(anyhow!("duplicate menu entry `{}`", name));
+        }
+        entries.push(MenuEntry {
+            name,
+            kernel_args,
+            modules,
+        });
+    }
+    if entries.is_empty() {
+        return Err(anyhow!("`menu-entries` must not be empty"));
+    }
+    Ok(entries)
+}
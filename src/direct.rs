@@ -0,0 +1,95 @@
+use crate::{elf::ElfClass, grub_cfg::Entry, multiboot::Multiboot1Header};
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+
+/// How QEMU boots the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMethod {
+    /// Boot an image created with GRUB, which loads the kernel with its multiboot2 header.
+    Grub,
+    /// Load the kernel with QEMU's `-kernel` option, which uses its multiboot header.
+    Direct,
+}
+
+impl BootMethod {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "grub" => Ok(BootMethod::Grub),
+            "direct" => Ok(BootMethod::Direct),
+            other => Err(anyhow!(
+                "unknown boot method `{}` (expected `grub` or `direct`)",
+                other
+            )),
+        }
+    }
+}
+
+/// A kernel loaded by QEMU's multiboot loader, without an image.
+#[derive(Debug)]
+pub struct DirectBoot {
+    kernel: PathBuf,
+    cmdline: String,
+    /// The `-initrd` argument, empty without modules.
+    initrd: String,
+}
+
+impl DirectBoot {
+    /// Prepares booting the kernel copied to `kernel` with the command line and modules of
+    /// `entry`, whose modules are installed below `sysroot`.
+    pub fn new(
+        kernel: &Path,
+        header: &Multiboot1Header,
+        entry: &Entry,
+        sysroot: &Path,
+    ) -> Result<DirectBoot> {
+        // QEMU only loads 32-bit ELF files itself, it would exit with "Cannot load x86-64
+        // image, give a 32bit one".
+        if header.elf.is_some_and(|elf| elf.class == ElfClass::Elf64) && !header.has_address() {
+            return Err(anyhow!(
+                "QEMU cannot load 64-bit ELF kernels directly, convert the kernel to ELF32 \
+                 (e.g. with `objcopy -O elf32-i386`) and run it with `qemu-binary = \
+                 \"qemu-system-x86_64\"`, set the address fields of its multiboot header or use \
+                 `boot-method = \"grub\"`"
+            ));
+        }
+
+        // QEMU splits the modules at commas, a doubled comma is a literal one. The path ends
+        // at the first space, the rest is the command line of the module.
+        let mut modules = Vec::new();
+        for module in &entry.modules {
+            let path = sysroot.join(module.dest.trim_start_matches('/'));
+            let path = path.display().to_string();
+            if path.contains(' ') {
+                return Err(anyhow!(
+                    "module `{}` cannot be loaded directly, its path contains a space",
+                    path
+                ));
+            }
+            let module = match &module.args {
+                Some(args) => format!("{} {}", path, args),
+                None => path,
+            };
+            modules.push(module.replace(',', ",,"));
+        }
+
+        Ok(DirectBoot {
+            kernel: kernel.to_owned(),
+            cmdline: entry.cmdline.clone(),
+            initrd: modules.join(","),
+        })
+    }
+
+    /// Returns the QEMU arguments loading the kernel and its modules.
+    pub fn qemu_args(&self) -> Vec<String> {
+        let mut args = vec!["-kernel".to_owned(), self.kernel.display().to_string()];
+        if !self.cmdline.is_empty() {
+            args.push("-append".to_owned());
+            args.push(self.cmdline.clone());
+        }
+        if !self.initrd.is_empty() {
+            args.push("-initrd".to_owned());
+            args.push(self.initrd.clone());
+        }
+        args
+    }
+}
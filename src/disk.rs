@@ -0,0 +1,755 @@
+//! Raw disk images with a partition table, built in user space.
+//!
+//! The boot partition is created as a separate file with mtools or mke2fs and copied into the
+//! image behind a hand-written MBR or GPT, so neither root nor loop devices are needed. For
+//! BIOS, GRUB's `boot.img` is written to the MBR and the `core.img` from `grub-mkimage` is
+//! embedded behind it (MBR) or into a BIOS boot partition (GPT), like `grub-install` does. For
+//! UEFI, the boot partition is an EFI system partition containing `EFI/BOOT/BOOTX64.EFI`.
+
+use crate::{
+    firmware::{self, Firmware},
+    staging::fnv1a,
+    tools::{Tool, Tools},
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    convert::TryFrom,
+    fs::{self, File},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// The kind of bootable image created for the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// A CD image created with `grub-mkrescue`.
+    Iso,
+    /// A hard disk image with a partition table.
+    Raw,
+}
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "iso" => Ok(ImageFormat::Iso),
+            "raw" | "img" => Ok(ImageFormat::Raw),
+            other => Err(anyhow!(
+                "unknown image format `{}` (expected `iso` or `raw`)",
+                other
+            )),
+        }
+    }
+
+    /// The file extension of images in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Iso => "iso",
+            ImageFormat::Raw => "img",
+        }
+    }
+}
+
+/// Formats of other hypervisors images can be converted to with `qemu-img`.
+pub const CONVERSION_FORMATS: &[&str] = &["qcow2", "vmdk", "vdi", "vhdx"];
+
+/// Converts `image` to `format` with `qemu-img` and returns the path of the converted image,
+/// which is next to `image` with the format as extension.
+pub fn convert_image(image: &Path, format: &str, tools: &Tools) -> Result<PathBuf> {
+    let out = image.with_extension(format);
+    let source_format = match image.extension().and_then(|extension| extension.to_str()) {
+        Some("img") | Some("iso") => "raw",
+        _ => return Err(anyhow!("cannot convert `{}`", image.display())),
+    };
+    run_tool(
+        tools
+            .command(Tool::QemuImg)
+            .args(["convert", "-f", source_format, "-O", format])
+            .arg(image)
+            .arg(&out),
+        Tool::QemuImg,
+        tools,
+    )?;
+    Ok(out)
+}
+
+/// Creates the qcow2 image `overlay` storing all writes to the qcow2 image `base`, which is
+/// left unchanged.
+pub fn create_overlay(base: &Path, overlay: &Path, tools: &Tools) -> Result<()> {
+    if overlay.exists() {
+        fs::remove_file(overlay)
+            .with_context(|| format!("Failed to remove `{}`", overlay.display()))?;
+    }
+    run_tool(
+        tools
+            .command(Tool::QemuImg)
+            .args(["create", "-f", "qcow2", "-F", "qcow2", "-b"])
+            .arg(base)
+            .arg(overlay),
+        Tool::QemuImg,
+        tools,
+    )
+}
+
+/// The partition table of a raw disk image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionTable {
+    Mbr,
+    Gpt,
+}
+
+impl PartitionTable {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "mbr" | "msdos" => Ok(PartitionTable::Mbr),
+            "gpt" => Ok(PartitionTable::Gpt),
+            other => Err(anyhow!(
+                "unknown partition table `{}` (expected `mbr` or `gpt`)",
+                other
+            )),
+        }
+    }
+}
+
+/// The file system of the boot partition of a raw disk image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filesystem {
+    /// FAT32, created with mtools.
+    Fat,
+    /// ext2, created with `mke2fs`.
+    Ext2,
+}
+
+impl Filesystem {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "fat" | "vfat" | "fat32" => Ok(Filesystem::Fat),
+            "ext2" => Ok(Filesystem::Ext2),
+            other => Err(anyhow!(
+                "unknown file system `{}` (expected `fat` or `ext2`)",
+                other
+            )),
+        }
+    }
+
+    /// The GRUB module reading the file system.
+    fn grub_module(self) -> &'static str {
+        match self {
+            Filesystem::Fat => "fat",
+            Filesystem::Ext2 => "ext2",
+        }
+    }
+}
+
+const SECTOR_SIZE: u64 = 512;
+/// The first partition starts at 1 MiB, the gap before it holds `core.img` with an MBR.
+const PARTITION_ALIGNMENT: u64 = 2048;
+/// Sectors of the BIOS boot partition holding `core.img` with a GPT.
+const BIOS_BOOT_SECTORS: u64 = 2048;
+/// Sectors of the partition entry array of a GPT (128 entries of 128 bytes).
+const GPT_ENTRY_SECTORS: u64 = 32;
+
+/// Offset of the sector of `core.img` in `boot.img`.
+const BOOT_KERNEL_SECTOR: usize = 0x5c;
+/// Offset of the boot drive check in `boot.img`, replaced by `nop`s for hard disks.
+const BOOT_DRIVE_CHECK: usize = 0x66;
+/// Offset of the block list at the end of the first sector of `core.img`.
+const CORE_BLOCKLIST: usize = 0x1f4;
+
+const GUID_BIOS_BOOT: &str = "21686148-6449-6E6F-744E-656564454649";
+const GUID_EFI_SYSTEM: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+const GUID_BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+const GUID_LINUX_FS: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+
+/// Creates the raw disk image `out` booting the contents of `sysroot` with `firmware`.
+///
+/// Intermediate files are created in `work_dir`.
+pub fn create_disk_image(
+    out: &Path,
+    sysroot: &Path,
+    work_dir: &Path,
+    table: PartitionTable,
+    filesystem: Filesystem,
+    firmware: &[Firmware],
+    tools: &Tools,
+) -> Result<()> {
+    let bios = firmware.contains(&Firmware::Bios);
+    let uefi = firmware.contains(&Firmware::Uefi);
+    if uefi && filesystem != Filesystem::Fat {
+        return Err(anyhow!(
+            "UEFI firmware only boots from FAT, set `filesystem = \"fat\"`"
+        ));
+    }
+
+    let (boot_start, boot_number) = match (table, bios) {
+        (PartitionTable::Gpt, true) => (PARTITION_ALIGNMENT + BIOS_BOOT_SECTORS, 2),
+        _ => (PARTITION_ALIGNMENT, 1),
+    };
+
+    for &firmware in firmware {
+        install_grub_modules(firmware, sysroot, tools)?;
+    }
+    if uefi {
+        let efi_dir = sysroot.join("EFI/BOOT");
+        fs::create_dir_all(&efi_dir)?;
+        // The EFI GRUB uses the partition it was loaded from as root.
+        grub_mkimage(
+            Firmware::Uefi,
+            "/boot/grub",
+            filesystem,
+            &efi_dir.join("BOOTX64.EFI"),
+            tools,
+        )?;
+    }
+    let core = if bios {
+        // An empty drive in the prefix refers to the drive the BIOS booted from.
+        let prefix = match table {
+            PartitionTable::Mbr => format!("(,msdos{})/boot/grub", boot_number),
+            PartitionTable::Gpt => format!("(,gpt{})/boot/grub", boot_number),
+        };
+        let core_path = work_dir.join("core.img");
+        grub_mkimage(Firmware::Bios, &prefix, filesystem, &core_path, tools)?;
+        Some(fs::read(&core_path).context("Failed to read core.img")?)
+    } else {
+        None
+    };
+
+    let partition = work_dir.join("boot.part");
+    let boot_sectors = partition_sectors(sysroot, filesystem)?;
+    create_filesystem(
+        &partition,
+        filesystem,
+        boot_sectors,
+        boot_start,
+        sysroot,
+        tools,
+    )?;
+
+    let total_sectors = match table {
+        PartitionTable::Mbr => boot_start + boot_sectors,
+        PartitionTable::Gpt => boot_start + boot_sectors + GPT_ENTRY_SECTORS + 1,
+    };
+    let seed = fnv1a(out.to_string_lossy().as_bytes());
+
+    let mut disk =
+        File::create(out).with_context(|| format!("Failed to create `{}`", out.display()))?;
+    disk.set_len(total_sectors * SECTOR_SIZE)?;
+
+    let mut mbr = [0u8; 512];
+    let core_sector = match table {
+        PartitionTable::Mbr => 1,
+        PartitionTable::Gpt => PARTITION_ALIGNMENT,
+    };
+    if let Some(core) = &core {
+        let embedding_sectors = match table {
+            PartitionTable::Mbr => PARTITION_ALIGNMENT - 1,
+            PartitionTable::Gpt => BIOS_BOOT_SECTORS,
+        };
+        let core = embed_core(core, core_sector, embedding_sectors)?;
+        write_at(&mut disk, core_sector, &core)?;
+        write_boot_img(&mut mbr, core_sector, tools)?;
+    }
+    match table {
+        PartitionTable::Mbr => {
+            let partition_type = match (filesystem, bios) {
+                (Filesystem::Fat, false) => 0xef,
+                (Filesystem::Fat, true) => 0x0c,
+                (Filesystem::Ext2, _) => 0x83,
+            };
+            mbr[0x1b8..0x1bc].copy_from_slice(&(seed as u32).to_le_bytes());
+            write_mbr_partition(&mut mbr, 0, 0x80, partition_type, boot_start, boot_sectors)?;
+        }
+        PartitionTable::Gpt => {
+            let protective_sectors = (total_sectors - 1).min(u64::from(u32::MAX));
+            write_mbr_partition(&mut mbr, 0, 0, 0xee, 1, protective_sectors)?;
+            let boot_type = match (filesystem, uefi) {
+                (Filesystem::Fat, true) => GUID_EFI_SYSTEM,
+                (Filesystem::Fat, false) => GUID_BASIC_DATA,
+                (Filesystem::Ext2, _) => GUID_LINUX_FS,
+            };
+            let mut partitions = Vec::new();
+            if bios {
+                partitions.push(GptPartition {
+                    type_guid: GUID_BIOS_BOOT,
+                    first: PARTITION_ALIGNMENT,
+                    sectors: BIOS_BOOT_SECTORS,
+                    name: "BIOS boot",
+                });
+            }
+            partitions.push(GptPartition {
+                type_guid: boot_type,
+                first: boot_start,
+                sectors: boot_sectors,
+                name: "boot",
+            });
+            write_gpt(&mut disk, total_sectors, &partitions, seed)?;
+        }
+    }
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+    write_at(&mut disk, 0, &mbr)?;
+
+    let mut partition_file = File::open(&partition)
+        .with_context(|| format!("Failed to open `{}`", partition.display()))?;
+    disk.seek(SeekFrom::Start(boot_start * SECTOR_SIZE))?;
+    io::copy(&mut partition_file, &mut disk)
+        .with_context(|| format!("Failed to write `{}`", out.display()))?;
+    fs::remove_file(&partition)?;
+    Ok(())
+}
+
+/// Copies the GRUB modules of `firmware` to `/boot/grub/<platform>`, where GRUB loads them
+/// from at runtime.
+fn install_grub_modules(firmware: Firmware, sysroot: &Path, tools: &Tools) -> Result<()> {
+    let source = platform_dir(firmware, tools)?;
+    let dest = sysroot.join("boot/grub").join(firmware.grub_platform());
+    fs::create_dir_all(&dest)?;
+    for entry in fs::read_dir(&source)? {
+        let path = entry?.path();
+        let is_module = path
+            .extension()
+            .is_some_and(|extension| extension == "mod" || extension == "lst");
+        if let (true, Some(name)) = (is_module, path.file_name()) {
+            fs::copy(&path, dest.join(name))
+                .with_context(|| format!("Failed to copy `{}`", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds the GRUB image of `firmware` with `grub-mkimage`.
+fn grub_mkimage(
+    firmware: Firmware,
+    prefix: &str,
+    filesystem: Filesystem,
+    out: &Path,
+    tools: &Tools,
+) -> Result<()> {
+    let (format, modules): (_, &[&str]) = match firmware {
+        Firmware::Bios => ("i386-pc", &["biosdisk", "part_msdos", "part_gpt"]),
+        Firmware::Uefi => ("x86_64-efi", &["part_msdos", "part_gpt", "efi_gop"]),
+    };
+    let mut cmd = tools.command(Tool::GrubMkimage);
+    cmd.arg("-O").arg(format);
+    cmd.arg("-d").arg(platform_dir(firmware, tools)?);
+    cmd.arg("-p").arg(prefix);
+    cmd.arg("-o").arg(out);
+    cmd.args(modules);
+    cmd.args([
+        filesystem.grub_module(),
+        "normal",
+        "configfile",
+        "multiboot2",
+    ]);
+    run_tool(&mut cmd, Tool::GrubMkimage, tools)
+}
+
+fn platform_dir(firmware: Firmware, tools: &Tools) -> Result<PathBuf> {
+    firmware::grub_platform_dir(firmware.grub_platform(), tools).ok_or_else(|| {
+        anyhow!(
+            "GRUB platform {} not found, {}",
+            firmware.grub_platform(),
+            firmware.grub_platform_hint()
+        )
+    })
+}
+
+/// Returns the size of a boot partition holding the files of `sysroot`, in sectors.
+fn partition_sectors(sysroot: &Path, filesystem: Filesystem) -> Result<u64> {
+    fn content_size(dir: &Path) -> Result<u64> {
+        let mut size = 0;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            // Every file and directory takes at least one 4 KiB block or cluster.
+            size += 4096;
+            size += if metadata.is_dir() {
+                content_size(&entry.path())?
+            } else {
+                metadata.len()
+            };
+        }
+        Ok(size)
+    }
+    // FAT32 needs at least 65525 clusters.
+    let minimum = match filesystem {
+        Filesystem::Fat => 64 << 20,
+        Filesystem::Ext2 => 8 << 20,
+    };
+    let size = (content_size(sysroot)? * 5 / 4 + (1 << 20)).max(minimum);
+    let sectors = size.div_ceil(SECTOR_SIZE);
+    Ok(sectors.div_ceil(PARTITION_ALIGNMENT) * PARTITION_ALIGNMENT)
+}
+
+/// Creates the file system image `path` of `sectors` sectors containing `sysroot`.
+///
+/// `start` is the first sector of the partition on the disk.
+fn create_filesystem(
+    path: &Path,
+    filesystem: Filesystem,
+    sectors: u64,
+    start: u64,
+    sysroot: &Path,
+    tools: &Tools,
+) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create `{}`", path.display()))?;
+    file.set_len(sectors * SECTOR_SIZE)?;
+    drop(file);
+
+    match filesystem {
+        Filesystem::Fat => {
+            run_tool(
+                tools
+                    .command(Tool::Mformat)
+                    .arg("-i")
+                    .arg(path)
+                    .arg("-F")
+                    .args(["-h", "64", "-s", "32"])
+                    .arg("-T")
+                    .arg(sectors.to_string())
+                    .arg("-H")
+                    .arg(start.to_string())
+                    .args(["-v", "BOOT", "::"]),
+                Tool::Mformat,
+                tools,
+            )?;
+            let mut cmd = tools.command(Tool::Mcopy);
+            cmd.arg("-s").arg("-Q").arg("-i").arg(path);
+            for entry in fs::read_dir(sysroot)? {
+                cmd.arg(entry?.path());
+            }
+            cmd.arg("::/");
+            run_tool(&mut cmd, Tool::Mcopy, tools)
+        }
+        Filesystem::Ext2 => run_tool(
+            tools
+                .command(Tool::Mke2fs)
+                .args([
+                    "-q",
+                    "-F",
+                    "-t",
+                    "ext2",
+                    "-L",
+                    "BOOT",
+                    "-E",
+                    "root_owner=0:0",
+                ])
+                .arg("-d")
+                .arg(sysroot)
+                .arg(path)
+                .arg(format!("{}k", sectors * SECTOR_SIZE / 1024)),
+            Tool::Mke2fs,
+            tools,
+        ),
+    }
+}
+
+/// Runs `cmd` of `tool`, turning a missing tool or a failure into a descriptive error.
+fn run_tool(cmd: &mut Command, tool: Tool, tools: &Tools) -> Result<()> {
+    let output = cmd.output().map_err(|err| tools.launch_error(tool, err))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} failed ({}):\n\n{}",
+            tool.name(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Patches the block list of `core.img` for being embedded at `sector`, like
+/// `grub-bios-setup` does, and pads it to whole sectors.
+fn embed_core(core: &[u8], sector: u64, available: u64) -> Result<Vec<u8>> {
+    let sectors = (core.len() as u64).div_ceil(SECTOR_SIZE);
+    if sectors > available || sectors < 2 {
+        return Err(anyhow!(
+            "core.img has {} sectors, but only {} are available to embed it",
+            sectors,
+            available
+        ));
+    }
+    let mut core = core.to_vec();
+    core.resize((sectors * SECTOR_SIZE) as usize, 0);
+    // The first sector loads the rest of the image, which directly follows it.
+    core[CORE_BLOCKLIST..CORE_BLOCKLIST + 8].copy_from_slice(&(sector + 1).to_le_bytes());
+    let rest = u16::try_from(sectors - 1)?;
+    core[CORE_BLOCKLIST + 8..CORE_BLOCKLIST + 10].copy_from_slice(&rest.to_le_bytes());
+    Ok(core)
+}
+
+/// Writes the boot code of GRUB's `boot.img` to `mbr`, loading `core.img` from `core_sector`.
+fn write_boot_img(mbr: &mut [u8; 512], core_sector: u64, tools: &Tools) -> Result<()> {
+    let path = platform_dir(Firmware::Bios, tools)?.join("boot.img");
+    let boot = fs::read(&path).with_context(|| format!("Failed to read `{}`", path.display()))?;
+    if boot.len() != 512 {
+        return Err(anyhow!("`{}` is not a boot sector", path.display()));
+    }
+    // Only the code is copied, the disk signature and partition table follow it.
+    mbr[..0x1b8].copy_from_slice(&boot[..0x1b8]);
+    mbr[BOOT_KERNEL_SECTOR..BOOT_KERNEL_SECTOR + 8].copy_from_slice(&core_sector.to_le_bytes());
+    // Some BIOSes pass a wrong boot drive, which the check would reject for hard disks.
+    mbr[BOOT_DRIVE_CHECK..BOOT_DRIVE_CHECK + 2].copy_from_slice(&[0x90, 0x90]);
+    Ok(())
+}
+
+/// Writes entry `index` of the MBR partition table, addressed by LBA only.
+fn write_mbr_partition(
+    mbr: &mut [u8; 512],
+    index: usize,
+    status: u8,
+    partition_type: u8,
+    first: u64,
+    sectors: u64,
+) -> Result<()> {
+    let first = u32::try_from(first)?;
+    let sectors =
+        u32::try_from(sectors).map_err(|_| anyhow!("the disk image is too large for an MBR"))?;
+    let entry = &mut mbr[0x1be + 16 * index..0x1be + 16 * (index + 1)];
+    entry[0] = status;
+    // CHS addresses beyond the first 8 GiB, which makes firmware use the LBA fields.
+    entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[4] = partition_type;
+    entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&first.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    Ok(())
+}
+
+struct GptPartition {
+    type_guid: &'static str,
+    first: u64,
+    sectors: u64,
+    name: &'static str,
+}
+
+/// Writes the primary and backup GPT of a disk with `total_sectors` sectors.
+///
+/// The GUIDs of the disk and partitions are derived from `seed`, so rebuilding an image
+/// does not change them.
+fn write_gpt(
+    disk: &mut File,
+    total_sectors: u64,
+    partitions: &[GptPartition],
+    seed: u64,
+) -> Result<()> {
+    let mut entries = vec![0u8; (GPT_ENTRY_SECTORS * SECTOR_SIZE) as usize];
+    for (index, partition) in partitions.iter().enumerate() {
+        let entry = &mut entries[128 * index..128 * (index + 1)];
+        entry[0..16].copy_from_slice(&parse_guid(partition.type_guid));
+        entry[16..32].copy_from_slice(&derive_guid(seed, index as u64 + 1));
+        entry[32..40].copy_from_slice(&partition.first.to_le_bytes());
+        let last = partition.first + partition.sectors - 1;
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        for (i, unit) in partition.name.encode_utf16().take(36).enumerate() {
+            entry[56 + 2 * i..58 + 2 * i].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    let entries_crc = crc32(&entries);
+
+    let last_sector = total_sectors - 1;
+    let disk_guid = derive_guid(seed, 0);
+    let header = |current: u64, backup: u64, entries_start: u64| {
+        let mut header = [0u8; 512];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&current.to_le_bytes());
+        header[32..40].copy_from_slice(&backup.to_le_bytes());
+        header[40..48].copy_from_slice(&(2 + GPT_ENTRY_SECTORS).to_le_bytes());
+        header[48..56].copy_from_slice(&(last_sector - GPT_ENTRY_SECTORS - 1).to_le_bytes());
+        header[56..72].copy_from_slice(&disk_guid);
+        header[72..80].copy_from_slice(&entries_start.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let header_crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        header
+    };
+
+    let backup_entries = last_sector - GPT_ENTRY_SECTORS;
+    write_at(disk, 1, &header(1, last_sector, 2))?;
+    write_at(disk, 2, &entries)?;
+    write_at(disk, backup_entries, &entries)?;
+    write_at(disk, last_sector, &header(last_sector, 1, backup_entries))?;
+    Ok(())
+}
+
+fn write_at(disk: &mut File, sector: u64, data: &[u8]) -> Result<()> {
+    disk.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+    disk.write_all(data)?;
+    Ok(())
+}
+
+/// Converts a GUID like `C12A7328-F81F-11D2-BA4B-00A0C93EC93B` to its on-disk form, in which
+/// the first three groups are little-endian.
+fn parse_guid(guid: &str) -> [u8; 16] {
+    let hex: Vec<u8> = guid
+        .split('-')
+        .flat_map(|group| {
+            (0..group.len())
+                .step_by(2)
+                .map(move |i| u8::from_str_radix(&group[i..i + 2], 16).unwrap())
+        })
+        .collect();
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hex);
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    bytes
+}
+
+/// Derives a random-looking (version 4) GUID from `seed` and `index`.
+fn derive_guid(seed: u64, index: u64) -> [u8; 16] {
+    let high = fnv1a(&[seed.to_le_bytes(), index.to_le_bytes()].concat());
+    let low = fnv1a(&high.to_le_bytes());
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&high.to_le_bytes());
+    bytes[8..].copy_from_slice(&low.to_le_bytes());
+    bytes[7] = (bytes[7] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    bytes
+}
+
+/// The CRC-32 (IEEE 802.3) used by GPT.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{u32_at, u64_at, TempDir};
+    use std::io::Read;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn guid_on_disk_bytes() {
+        assert_eq!(
+            parse_guid(GUID_EFI_SYSTEM),
+            [
+                0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+                0xc9, 0x3b
+            ]
+        );
+        assert_eq!(
+            parse_guid(GUID_BIOS_BOOT),
+            *b"Hah!IdontNeedEFI",
+            "the BIOS boot partition GUID spells its well-known ASCII form"
+        );
+    }
+
+    #[test]
+    fn derived_guids_are_stable_version_4() {
+        let guid = derive_guid(42, 1);
+        assert_eq!(guid, derive_guid(42, 1));
+        assert_ne!(guid, derive_guid(42, 2));
+        assert_ne!(guid, derive_guid(43, 1));
+        assert_eq!(guid[7] >> 4, 4, "version");
+        assert_eq!(guid[8] >> 6, 0b10, "variant");
+    }
+
+    #[test]
+    fn embed_core_patches_blocklist() {
+        let core = vec![0xaa; 2 * 512 + 100];
+        let embedded = embed_core(&core, 1, 2047).unwrap();
+        assert_eq!(embedded.len(), 3 * 512);
+        assert_eq!(u64_at(&embedded, CORE_BLOCKLIST), 2);
+        assert_eq!(
+            u16::from_le_bytes([embedded[CORE_BLOCKLIST + 8], embedded[CORE_BLOCKLIST + 9]]),
+            2
+        );
+        assert_eq!(embedded[..CORE_BLOCKLIST], core[..CORE_BLOCKLIST]);
+        assert!(embedded[core.len()..].iter().all(|&byte| byte == 0));
+
+        let embedded = embed_core(&core, PARTITION_ALIGNMENT, BIOS_BOOT_SECTORS).unwrap();
+        assert_eq!(u64_at(&embedded, CORE_BLOCKLIST), PARTITION_ALIGNMENT + 1);
+    }
+
+    #[test]
+    fn embed_core_rejects_bad_sizes() {
+        assert!(embed_core(&[0; 512], 1, 2047).is_err());
+        assert!(embed_core(&[0; 4 * 512], 1, 3).is_err());
+    }
+
+    #[test]
+    fn write_boot_img_offsets() {
+        let dir = TempDir::new("boot-img");
+        let platform = dir.path().join("lib/grub/i386-pc");
+        fs::create_dir_all(&platform).unwrap();
+        fs::write(platform.join("modinfo.sh"), "").unwrap();
+        let boot: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        fs::write(platform.join("boot.img"), &boot).unwrap();
+        let mut tools = Tools::default();
+        tools.set(Tool::GrubMkimage, dir.path().join("bin/grub-mkimage"));
+
+        let mut mbr = [0xff; 512];
+        write_boot_img(&mut mbr, 2048, &tools).unwrap();
+        assert_eq!(u64_at(&mbr, BOOT_KERNEL_SECTOR), 2048);
+        assert_eq!(mbr[BOOT_DRIVE_CHECK..BOOT_DRIVE_CHECK + 2], [0x90, 0x90]);
+        assert_eq!(mbr[..BOOT_KERNEL_SECTOR], boot[..BOOT_KERNEL_SECTOR]);
+        assert_eq!(
+            mbr[BOOT_DRIVE_CHECK + 2..0x1b8],
+            boot[BOOT_DRIVE_CHECK + 2..0x1b8]
+        );
+        // The disk signature and partition table are left alone.
+        assert!(mbr[0x1b8..].iter().all(|&byte| byte == 0xff));
+    }
+
+    #[test]
+    fn gpt_headers() {
+        let dir = TempDir::new("gpt");
+        let path = dir.path().join("disk.img");
+        let total_sectors = 8192;
+        let mut disk = File::create(&path).unwrap();
+        disk.set_len(total_sectors * SECTOR_SIZE).unwrap();
+        let partitions = [GptPartition {
+            type_guid: GUID_EFI_SYSTEM,
+            first: PARTITION_ALIGNMENT,
+            sectors: 4096,
+            name: "boot",
+        }];
+        write_gpt(&mut disk, total_sectors, &partitions, 7).unwrap();
+        drop(disk);
+        let mut image = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut image).unwrap();
+        let sector = |lba: u64| &image[(lba * SECTOR_SIZE) as usize..][..SECTOR_SIZE as usize];
+
+        let entries = &image[2 * 512..(2 + GPT_ENTRY_SECTORS as usize) * 512];
+        assert_eq!(entries[..16], parse_guid(GUID_EFI_SYSTEM));
+        assert_eq!(u64_at(entries, 32), 2048);
+        assert_eq!(u64_at(entries, 40), 2048 + 4096 - 1);
+        assert_eq!(entries[56..64], *b"b\0o\0o\0t\0");
+
+        for &(lba, backup, entries_start) in &[(1, 8191, 2), (8191, 1, 8191 - 32)] {
+            let header = sector(lba);
+            assert_eq!(header[..8], *b"EFI PART");
+            assert_eq!(u64_at(header, 24), lba);
+            assert_eq!(u64_at(header, 32), backup);
+            assert_eq!(u64_at(header, 40), 34, "first usable LBA");
+            assert_eq!(u64_at(header, 48), 8191 - 33, "last usable LBA");
+            assert_eq!(u64_at(header, 72), entries_start);
+            assert_eq!(u32_at(header, 88), crc32(entries));
+            let mut copy = header[..92].to_vec();
+            copy[16..20].fill(0);
+            assert_eq!(u32_at(header, 16), crc32(&copy));
+        }
+        assert_eq!(sector(8191 - 32)[..128], entries[..128]);
+    }
+}
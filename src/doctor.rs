@@ -0,0 +1,176 @@
+use crate::{
+    config::Config,
+    direct::BootMethod,
+    disk::{Filesystem, ImageFormat},
+    firmware::{self, Firmware, Ovmf},
+    run::AUTO_QEMU,
+    tools::{self, Tool},
+};
+use std::{env, path::Path, process::Command};
+
+/// The QEMU system emulators `qemu-binary = "auto"` chooses from, with what they are needed
+/// for and whether they are required.
+const EMULATORS: &[(&str, &str, bool)] = &[
+    ("qemu-system-x86_64", "running the kernel", true),
+    (
+        "qemu-system-i386",
+        "running 32-bit kernels booted with BIOS",
+        false,
+    ),
+];
+/// How the QEMU system emulators are usually installed.
+const QEMU_HINT: &str = "install the `qemu-system-x86` package";
+
+/// Firmware whose GRUB platform grub-mkrescue can put into the ISO.
+const PLATFORMS: &[Firmware] = &[Firmware::Bios, Firmware::Uefi];
+
+/// Checks the host toolchain and prints a report. Returns the exit code of the tool.
+///
+/// The tools are looked up at the paths set in `config` and the environment, the configured
+/// OVMF files relative to `manifest_dir`.
+pub fn run(config: &Config, manifest_dir: &Path) -> i32 {
+    let mut missing = Vec::new();
+    let mut failed = false;
+
+    for &tool in Tool::ALL {
+        let path = config.tools.path(tool);
+        let required = is_required(tool, config);
+        let purpose = format!("{}{}", tool.purpose(), unused(required));
+        if !check(tool.name(), &path, tool.version_args(), &purpose) {
+            missing.push(format!("{}: {}", tool.name(), tool.hint()));
+            failed |= required;
+        }
+    }
+
+    let qemu = env::var(tools::QEMU_ENV_VAR)
+        .ok()
+        .filter(|qemu| !qemu.is_empty())
+        .or_else(|| config.qemu_binary.clone());
+    let emulators = match qemu.as_deref() {
+        Some(AUTO_QEMU) | None => EMULATORS.to_vec(),
+        Some(qemu) => vec![(qemu, EMULATORS[0].1, true)],
+    };
+    for (name, purpose, required) in emulators {
+        if !check(name, Path::new(name), &["--version"], purpose) {
+            missing.push(format!("{}: {}", name, QEMU_HINT));
+            // Only 32-bit kernels need qemu-system-i386, which the configuration cannot tell.
+            failed |= required;
+        }
+    }
+
+    let uses_grub = config.boot_method == BootMethod::Grub;
+    let firmware = configured_firmware(config);
+    for &platform_firmware in PLATFORMS {
+        let platform = platform_firmware.grub_platform();
+        let required = uses_grub && firmware.contains(&platform_firmware);
+        match firmware::grub_platform_dir(platform, &config.tools) {
+            Some(dir) => println!("[ok]      GRUB platform {}: {}", platform, dir.display()),
+            None => {
+                println!("[missing] GRUB platform {}{}", platform, unused(required));
+                missing.push(format!(
+                    "GRUB platform {}: {}",
+                    platform,
+                    platform_firmware.grub_platform_hint()
+                ));
+                failed |= required;
+            }
+        }
+    }
+
+    let required = firmware.contains(&Firmware::Uefi);
+    let path = |path: &Option<String>| path.as_ref().map(|path| manifest_dir.join(path));
+    match Ovmf::locate(path(&config.ovmf_code), path(&config.ovmf_vars)) {
+        Ok(ovmf) => println!("[ok]      OVMF: {}", ovmf.code.display()),
+        Err(error) => {
+            println!(
+                "[missing] OVMF: needed for `firmware = \"uefi\"`{}",
+                unused(required)
+            );
+            missing.push(error.to_string());
+            failed |= required;
+        }
+    }
+
+    if missing.is_empty() {
+        println!("\nEverything grub-bootimage needs is installed.");
+        return 0;
+    }
+    println!("\nTo fix the missing pieces:");
+    for fix in &missing {
+        println!("    {}", fix);
+    }
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+/// Whether the images of `config` cannot be built or converted without `tool`.
+fn is_required(tool: Tool, config: &Config) -> bool {
+    let grub = config.boot_method == BootMethod::Grub;
+    let iso = grub && config.image_format == ImageFormat::Iso;
+    let raw = grub && config.image_format == ImageFormat::Raw;
+    match tool {
+        Tool::Cargo => true,
+        Tool::GrubMkrescue | Tool::Xorriso => iso,
+        // grub-mkrescue creates the EFI image of the ISO with mformat.
+        Tool::Mformat => iso || (raw && config.filesystem == Filesystem::Fat),
+        Tool::GrubMkimage => raw,
+        Tool::Mcopy => raw && config.filesystem == Filesystem::Fat,
+        Tool::Mke2fs => raw && config.filesystem == Filesystem::Ext2,
+        Tool::QemuImg => config
+            .convert_to
+            .as_ref()
+            .is_some_and(|formats| !formats.is_empty()),
+    }
+}
+
+/// Returns the firmware the kernel is booted with in any mode.
+fn configured_firmware(config: &Config) -> Vec<Firmware> {
+    let mut firmware = vec![config.firmware];
+    firmware.extend(config.test_firmware.iter().flatten().copied());
+    firmware
+}
+
+/// The note on a missing piece that the configuration does not use.
+fn unused(required: bool) -> &'static str {
+    if required {
+        ""
+    } else {
+        " (not used by this configuration)"
+    }
+}
+
+/// Probes the program `name` at `path` and prints whether it was found. Returns whether it was.
+fn check(name: &str, path: &Path, version_args: &[&str], purpose: &str) -> bool {
+    let label = if path.as_os_str() == name {
+        name.to_owned()
+    } else {
+        format!("{} ({})", name, path.display())
+    };
+    match probe_version(path, version_args) {
+        Some(version) => {
+            println!("[ok]      {}: {}", label, version);
+            true
+        }
+        None => {
+            println!("[missing] {}: needed for {}", label, purpose);
+            false
+        }
+    }
+}
+
+/// Runs `name` with `args` and returns the first line of its output.
+fn probe_version(name: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new(name).args(args).output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line = stdout
+        .lines()
+        .chain(stderr.lines())
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or("unknown version");
+    Some(line.to_owned())
+}
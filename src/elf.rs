@@ -0,0 +1,61 @@
+use anyhow::{anyhow, Result};
+use std::convert::TryInto;
+
+/// `e_machine` of 32-bit x86 executables.
+pub const EM_386: u16 = 3;
+/// `e_machine` of MIPS executables.
+pub const EM_MIPS: u16 = 8;
+/// `e_machine` of x86_64 executables.
+pub const EM_X86_64: u16 = 62;
+
+/// The word size of an ELF file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfClass {
+    Elf32,
+    Elf64,
+}
+
+/// The parts of the ELF file header grub-bootimage needs.
+#[derive(Debug, Clone, Copy)]
+pub struct ElfHeader {
+    pub class: ElfClass,
+    /// The `e_machine` field, i.e. the instruction set.
+    pub machine: u16,
+}
+
+impl ElfHeader {
+    /// Returns a human readable name of the instruction set.
+    pub fn machine_name(&self) -> String {
+        match self.machine {
+            EM_386 => "i386".to_owned(),
+            EM_MIPS => "MIPS".to_owned(),
+            EM_X86_64 => "x86_64".to_owned(),
+            other => format!("machine {}", other),
+        }
+    }
+}
+
+/// Returns whether `data` starts with the ELF magic.
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(b"\x7fELF")
+}
+
+/// Parses the file header of the little-endian ELF file in `data`.
+pub fn parse_header(data: &[u8]) -> Result<ElfHeader> {
+    if !is_elf(data) {
+        return Err(anyhow!("not an ELF file"));
+    }
+    if data.len() < 0x40 {
+        return Err(anyhow!("ELF header is truncated"));
+    }
+    let class = match data[4] {
+        1 => ElfClass::Elf32,
+        2 => ElfClass::Elf64,
+        other => return Err(anyhow!("invalid ELF class {}", other)),
+    };
+    if data[5] != 1 {
+        return Err(anyhow!("big-endian ELF files are not supported"));
+    }
+    let machine = u16::from_le_bytes(data[0x12..0x14].try_into().unwrap());
+    Ok(ElfHeader { class, machine })
+}
@@ -0,0 +1,196 @@
+use crate::tools::{Tool, Tools};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+
+/// The firmware QEMU boots the image with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firmware {
+    /// QEMU's default SeaBIOS, booting the i386-pc GRUB.
+    Bios,
+    /// OVMF, booting the x86_64-efi GRUB.
+    Uefi,
+}
+
+impl Firmware {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "bios" => Ok(Firmware::Bios),
+            "uefi" => Ok(Firmware::Uefi),
+            other => Err(anyhow!(
+                "unknown firmware `{}` (expected `bios` or `uefi`)",
+                other
+            )),
+        }
+    }
+
+    /// The GRUB platform booted by the firmware.
+    pub fn grub_platform(self) -> &'static str {
+        match self {
+            Firmware::Bios => "i386-pc",
+            Firmware::Uefi => "x86_64-efi",
+        }
+    }
+
+    /// How the platform files of the firmware are usually installed.
+    pub fn grub_platform_hint(self) -> &'static str {
+        match self {
+            Firmware::Bios => "install the `grub-pc-bin` package (or `grub2-pc-modules`)",
+            Firmware::Uefi => {
+                "install the `grub-efi-amd64-bin` package (or `grub2-efi-x64-modules`)"
+            }
+        }
+    }
+}
+
+impl fmt::Display for Firmware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Firmware::Bios => write!(f, "bios"),
+            Firmware::Uefi => write!(f, "uefi"),
+        }
+    }
+}
+
+/// A firmware and QEMU machine type combination a kernel is booted on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    pub firmware: Firmware,
+    /// The QEMU machine type, e.g. `q35`, QEMU's default if `None`.
+    pub machine: Option<String>,
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.machine {
+            Some(machine) => write!(f, "{}, {}", self.firmware, machine),
+            None => write!(f, "{}", self.firmware),
+        }
+    }
+}
+
+/// The OVMF firmware files QEMU needs to boot with UEFI.
+#[derive(Debug, Clone)]
+pub struct Ovmf {
+    /// The read-only firmware code.
+    pub code: PathBuf,
+    /// The variable store, copied for every run because the firmware writes to it.
+    pub vars: PathBuf,
+}
+
+impl Ovmf {
+    /// Returns the configured OVMF files, or the first pair found in the usual locations.
+    pub fn locate(code: Option<PathBuf>, vars: Option<PathBuf>) -> Result<Ovmf> {
+        match (code, vars) {
+            (Some(code), Some(vars)) => {
+                for path in &[&code, &vars] {
+                    if !path.is_file() {
+                        return Err(anyhow!("OVMF file `{}` not found", path.display()));
+                    }
+                }
+                Ok(Ovmf { code, vars })
+            }
+            (None, None) => Ovmf::find().ok_or_else(|| {
+                anyhow!(
+                    "OVMF not found, install the `ovmf` package (or `edk2-ovmf`) or set \
+                     `ovmf-code` and `ovmf-vars`"
+                )
+            }),
+            _ => Err(anyhow!("`ovmf-code` and `ovmf-vars` must be set together")),
+        }
+    }
+
+    /// Searches the paths distributions install OVMF to.
+    pub fn find() -> Option<Ovmf> {
+        OVMF_PATHS
+            .iter()
+            .map(|(code, vars)| Ovmf {
+                code: PathBuf::from(code),
+                vars: PathBuf::from(vars),
+            })
+            .find(|ovmf| ovmf.code.is_file() && ovmf.vars.is_file())
+    }
+
+    /// Copies the variable store to `vars` and returns the QEMU arguments booting with it.
+    pub fn qemu_args(&self, vars: &Path) -> Result<Vec<String>> {
+        fs::copy(&self.vars, vars)
+            .with_context(|| format!("Failed to copy OVMF variables `{}`", self.vars.display()))?;
+        Ok(vec![
+            "-drive".to_owned(),
+            format!(
+                "if=pflash,format=raw,unit=0,readonly=on,file={}",
+                self.code.display()
+            ),
+            "-drive".to_owned(),
+            format!("if=pflash,format=raw,unit=1,file={}", vars.display()),
+        ])
+    }
+}
+
+/// Pairs of OVMF code and variable files as installed by common distributions.
+const OVMF_PATHS: &[(&str, &str)] = &[
+    // Debian, Ubuntu
+    (
+        "/usr/share/OVMF/OVMF_CODE.fd",
+        "/usr/share/OVMF/OVMF_VARS.fd",
+    ),
+    (
+        "/usr/share/OVMF/OVMF_CODE_4M.fd",
+        "/usr/share/OVMF/OVMF_VARS_4M.fd",
+    ),
+    // Fedora
+    (
+        "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+        "/usr/share/edk2/ovmf/OVMF_VARS.fd",
+    ),
+    // Arch Linux
+    (
+        "/usr/share/edk2/x64/OVMF_CODE.4m.fd",
+        "/usr/share/edk2/x64/OVMF_VARS.4m.fd",
+    ),
+    (
+        "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+        "/usr/share/edk2-ovmf/x64/OVMF_VARS.fd",
+    ),
+    // openSUSE
+    (
+        "/usr/share/qemu/ovmf-x86_64-code.bin",
+        "/usr/share/qemu/ovmf-x86_64-vars.bin",
+    ),
+];
+
+/// Returns the directory containing the GRUB files of `platform`, e.g. `x86_64-efi`.
+pub fn grub_platform_dir(platform: &str, tools: &Tools) -> Option<PathBuf> {
+    grub_lib_dirs(tools)
+        .iter()
+        .map(|dir| dir.join(platform))
+        .find(|dir| dir.join("modinfo.sh").exists())
+}
+
+/// Returns the directories in which GRUB's platform directories may be installed.
+fn grub_lib_dirs(tools: &Tools) -> Vec<PathBuf> {
+    // grub-mkrescue looks in `<prefix>/lib/grub` of its own installation prefix.
+    let mut dirs: Vec<PathBuf> = tools
+        .grub_prefixes()
+        .iter()
+        .map(|prefix| prefix.join("lib/grub"))
+        .collect();
+    if let Some(paths) = env::var_os("PATH") {
+        for bin in env::split_paths(&paths) {
+            if bin.join(Tool::GrubMkrescue.name()).exists() {
+                if let Some(prefix) = bin.parent() {
+                    dirs.push(prefix.join("lib/grub"));
+                }
+            }
+        }
+    }
+    for dir in &["/usr/lib/grub", "/usr/local/lib/grub", "/usr/share/grub"] {
+        let dir = Path::new(dir).to_owned();
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    dirs
+}
@@ -0,0 +1,301 @@
+use crate::{config::SerialConsole, modules::ResolvedModule, multiboot::Protocol, run::Mode};
+use anyhow::{anyhow, Context, Result};
+use std::{fs, path::Path};
+
+/// Path of the kernel in the image.
+pub const KERNEL_PATH: &str = "/boot/kernel.bin";
+
+/// An entry of the GRUB menu.
+#[derive(Debug)]
+pub struct Entry {
+    pub name: String,
+    pub cmdline: String,
+    pub modules: Vec<ResolvedModule>,
+}
+
+impl Entry {
+    /// The `multiboot2` and `module2` lines, or their multiboot counterparts, booting the
+    /// entry with `protocol`, one per line.
+    fn boot_lines(&self, protocol: Protocol) -> String {
+        let command = protocol.kernel_command();
+        let mut lines = vec![if self.cmdline.is_empty() {
+            format!("{} {}", command, KERNEL_PATH)
+        } else {
+            format!("{} {} {}", command, KERNEL_PATH, quote_args(&self.cmdline))
+        }];
+        lines.extend(self.module_lines(protocol));
+        lines.join("\n")
+    }
+
+    /// The `module2` or `module` lines loading the modules of the entry with `protocol`.
+    fn module_lines(&self, protocol: Protocol) -> Vec<String> {
+        let command = protocol.module_command();
+        self.modules
+            .iter()
+            .map(|module| match &module.args {
+                Some(args) => format!(
+                    "{} {} {}",
+                    command,
+                    quote_word(&module.dest),
+                    quote_args(args)
+                ),
+                None => format!("{} {}", command, quote_word(&module.dest)),
+            })
+            .collect()
+    }
+
+    fn menu_entry(&self, protocol: Protocol) -> String {
+        let mut menu_entry = format!("menuentry {} {{\n", quote_word(&self.name));
+        for line in self.boot_lines(protocol).lines() {
+            menu_entry.push_str(&format!("\t{}\n", line));
+        }
+        menu_entry.push_str("\tboot\n}");
+        menu_entry
+    }
+}
+
+/// The values substituted into a `grub.cfg` template.
+#[derive(Debug)]
+pub struct Variables<'a> {
+    pub package_name: &'a str,
+    pub entries: &'a [Entry],
+    /// Index of the entry booted by default.
+    pub default: usize,
+    /// Seconds GRUB waits before booting the default entry, `-1` to wait for a choice.
+    pub timeout: i32,
+    pub mode: Mode,
+    pub serial: Option<SerialConsole>,
+    /// The protocol the kernel is booted with.
+    pub protocol: Protocol,
+}
+
+impl Variables<'_> {
+    fn default_entry(&self) -> &Entry {
+        &self.entries[self.default]
+    }
+
+    /// The commands making GRUB use the serial console, empty without `grub-serial`.
+    fn serial_lines(&self) -> String {
+        match self.serial {
+            Some(serial) => format!(
+                "serial --unit={} --speed={}\n\
+                 terminal_input serial console\n\
+                 terminal_output serial console",
+                serial.unit, serial.speed
+            ),
+            None => String::new(),
+        }
+    }
+
+    fn menu_entries(&self) -> String {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|entry| entry.menu_entry(self.protocol))
+            .collect();
+        entries.join("\n")
+    }
+
+    /// Returns the value of the placeholder `name`, or `None` if it is unknown.
+    ///
+    /// `cmdline` and `modules` are the ones of the default entry.
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "kernel" => Some(KERNEL_PATH.to_owned()),
+            "cmdline" => Some(quote_args(&self.default_entry().cmdline)),
+            "modules" => Some(self.default_entry().module_lines(self.protocol).join("\n")),
+            "multiboot" => Some(self.protocol.kernel_command().to_owned()),
+            "module" => Some(self.protocol.module_command().to_owned()),
+            "entries" => Some(self.menu_entries()),
+            "timeout" => Some(self.timeout.to_string()),
+            "serial" => Some(self.serial_lines()),
+            "default" => Some(self.default.to_string()),
+            "package_name" => Some(self.package_name.to_owned()),
+            "is_test" => Some((self.mode != Mode::Run).to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Quotes the whitespace-separated words of a kernel or module command line for GRUB's
+/// script parser, which would otherwise expand `$variables`, remove quotes and end the
+/// command at a `;`.
+///
+/// GRUB passes the words on separated by spaces, so the kernel sees `args` unchanged.
+fn quote_args(args: &str) -> String {
+    let words: Vec<String> = args.split_whitespace().map(quote_word).collect();
+    words.join(" ")
+}
+
+/// Quotes `word` as a single word for GRUB's script parser, unless it consists only of
+/// characters without a special meaning.
+fn quote_word(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.,/:=+@%^~".contains(c));
+    if plain {
+        word.to_owned()
+    } else {
+        // A single quote cannot be escaped inside single quotes.
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// Returns the `grub.cfg` used when no template is configured.
+pub fn default_config(vars: &Variables) -> String {
+    let mut grub_config = String::new();
+    if vars.serial.is_some() {
+        grub_config.push_str(&vars.serial_lines());
+        grub_config.push('\n');
+    }
+    grub_config.push_str(&format!("set timeout={}\n", vars.timeout));
+    grub_config.push_str(&format!("set default={}\n", vars.default));
+    grub_config.push_str(&vars.menu_entries());
+    grub_config
+}
+
+/// Reads the template at `path` and substitutes the placeholders in it.
+pub fn render_template(path: &Path, vars: &Variables) -> Result<String> {
+    let template = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read grub.cfg template `{}`", path.display()))?;
+    render(&template, vars)
+        .with_context(|| format!("Invalid grub.cfg template `{}`", path.display()))
+}
+
+/// Replaces the placeholders like `{kernel}` in `template`.
+///
+/// Braces that do not enclose a placeholder name are kept, so GRUB's own `{ ... }` blocks and
+/// `${variables}` need no escaping. Values spanning several lines are indented like the line
+/// of their placeholder.
+fn render(template: &str, vars: &Variables) -> Result<String> {
+    let mut output = String::new();
+    for line in template.split_inclusive('\n') {
+        let indent: String = line
+            .chars()
+            .take_while(|c| c.is_whitespace() && *c != '\n')
+            .collect();
+        let mut rest = line;
+        while let Some(start) = rest.find('{') {
+            output.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let name = after
+                .find('}')
+                .map(|end| &after[..end])
+                .filter(|name| is_placeholder_name(name));
+            match name {
+                // GRUB variables like `${root}` are not placeholders.
+                Some(_) if output.ends_with('$') => {
+                    output.push('{');
+                    rest = after;
+                }
+                Some(name) => {
+                    let value = vars
+                        .get(name)
+                        .ok_or_else(|| anyhow!("unknown placeholder `{{{}}}`", name))?;
+                    output.push_str(&value.replace('\n', &format!("\n{}", indent)));
+                    rest = &after[name.len() + 1..];
+                }
+                None => {
+                    output.push('{');
+                    rest = after;
+                }
+            }
+        }
+        output.push_str(rest);
+    }
+    Ok(output)
+}
+
+fn is_placeholder_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::Content;
+
+    #[test]
+    fn quotes_metacharacters() {
+        assert_eq!(
+            quote_args("root=/dev/sda1 log=debug"),
+            "root=/dev/sda1 log=debug"
+        );
+        assert_eq!(quote_args("  a   b "), "a b");
+        assert_eq!(quote_args("home=$HOME"), "'home=$HOME'");
+        assert_eq!(quote_args("a;reboot"), "'a;reboot'");
+        assert_eq!(quote_args("msg=\"hi\""), "'msg=\"hi\"'");
+        assert_eq!(quote_args("it's"), "'it'\\''s'");
+        assert_eq!(quote_args(""), "");
+        assert_eq!(quote_word("kernel"), "kernel");
+        assert_eq!(quote_word("Run $x \\ \"y\""), "'Run $x \\ \"y\"'");
+        assert_eq!(quote_word(""), "''");
+    }
+
+    fn render_with(template: &str) -> Result<String> {
+        let entries = [Entry {
+            name: "kernel".to_owned(),
+            cmdline: "log=debug".to_owned(),
+            modules: vec![
+                ResolvedModule {
+                    content: Content::File("initrd".into()),
+                    dest: "/boot/initrd".to_owned(),
+                    args: Some("root".to_owned()),
+                },
+                ResolvedModule {
+                    content: Content::File("font".into()),
+                    dest: "/$font".to_owned(),
+                    args: None,
+                },
+            ],
+        }];
+        let vars = Variables {
+            package_name: "os",
+            entries: &entries,
+            default: 0,
+            timeout: 5,
+            mode: Mode::Test,
+            serial: None,
+            protocol: Protocol::Multiboot2,
+        };
+        render(template, &vars)
+    }
+
+    #[test]
+    fn renders_templates() {
+        let cases = [
+            (
+                "{multiboot} {kernel} {cmdline}\n",
+                "multiboot2 /boot/kernel.bin log=debug\n",
+            ),
+            ("set a={package_name}-{is_test}", "set a=os-true"),
+            // GRUB variables and blocks are kept.
+            ("echo ${root} $root {}", "echo ${root} $root {}"),
+            (
+                "if [ x$a = x ]; then { true; }; fi",
+                "if [ x$a = x ]; then { true; }; fi",
+            ),
+            ("{Kernel} {not a name} {", "{Kernel} {not a name} {"),
+            // Values spanning several lines are indented like their placeholder.
+            (
+                "menuentry x {\n\t{modules}\n}",
+                "menuentry x {\n\tmodule2 /boot/initrd root\n\tmodule2 '/$font'\n}",
+            ),
+            (
+                "  {entries}",
+                "  menuentry kernel {\n  \tmultiboot2 /boot/kernel.bin log=debug\n  \t\
+                 module2 /boot/initrd root\n  \tmodule2 '/$font'\n  \tboot\n  }",
+            ),
+        ];
+        for (template, expected) in &cases {
+            assert_eq!(render_with(template).unwrap(), *expected, "{:?}", template);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let error = render_with("multiboot2 {kernel} {cmd_line}").unwrap_err();
+        assert_eq!(error.to_string(), "unknown placeholder `{cmd_line}`");
+    }
+}
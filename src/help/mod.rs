@@ -0,0 +1,27 @@
+use crate::args::Subcommand;
+
+const HELP: &str = include_str!("help.txt");
+const BUILD_HELP: &str = include_str!("build_help.txt");
+const RUN_HELP: &str = include_str!("run_help.txt");
+const TEST_HELP: &str = include_str!("test_help.txt");
+const RUNNER_HELP: &str = include_str!("runner_help.txt");
+const CLEAN_HELP: &str = include_str!("clean_help.txt");
+const DOCTOR_HELP: &str = include_str!("doctor_help.txt");
+
+/// Prints the help text for the given subcommand, or the general help if `None`.
+pub fn print_help(subcommand: Option<Subcommand>) {
+    let help = match subcommand {
+        None => HELP,
+        Some(Subcommand::Build) => BUILD_HELP,
+        Some(Subcommand::Run) => RUN_HELP,
+        Some(Subcommand::Test) => TEST_HELP,
+        Some(Subcommand::Runner) => RUNNER_HELP,
+        Some(Subcommand::Clean) => CLEAN_HELP,
+        Some(Subcommand::Doctor) => DOCTOR_HELP,
+    };
+    print!("{}", help);
+}
+
+pub fn print_version() {
+    println!("grub-bootimage {}", env!("CARGO_PKG_VERSION"));
+}
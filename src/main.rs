@@ -1,145 +1,262 @@
-use anyhow::{anyhow, Context, Result};
-use cargo_metadata::MetadataCommand;
-use std::{
-    env, fs,
-    path::{Path, PathBuf},
-    process::{Command, Stdio},
-    time::Duration,
-};
-use wait_timeout::ChildExt;
+use anyhow::{anyhow, Result};
+use args::{Args, Command};
+use builder::Builder;
+use config::Config;
+use run::Mode;
+use std::{env, path::PathBuf, process};
 
+mod archive;
+mod args;
+mod builder;
 mod config;
+mod direct;
+mod disk;
+mod doctor;
+mod elf;
+mod firmware;
+mod grub_cfg;
+mod help;
+mod modules;
+mod multiboot;
+mod run;
+mod staging;
+#[cfg(test)]
+mod test_util;
+mod tools;
 
 pub fn main() -> Result<()> {
-    let mut raw_args = env::args();
-
-    match raw_args.nth(1).as_deref() {
-        Some("runner") => {}
-        Some("--help") => todo!(),
-        Some(any) => return Err(anyhow!("bootimage: Unrecognized option '{}'", any)),
-        None => {
-            return Err(anyhow!(
-                "bootimage: No operation specified (use --help for help)"
-            ))
+    let exit_code = match args::parse_args()? {
+        Command::Build(args) => build(args)?,
+        Command::Run(args) => run(args)?,
+        Command::Test(args) => test(args)?,
+        Command::Runner(args) => runner(args)?,
+        Command::Clean(args) => clean(args)?,
+        Command::Doctor(args) => {
+            let (config, manifest_dir) = doctor_config(&args)?;
+            doctor::run(&config, &manifest_dir)
+        }
+        Command::Help(subcommand) => {
+            help::print_help(subcommand);
+            0
+        }
+        Command::Version => {
+            help::print_version();
+            0
         }
     };
-
-    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
-    let mut cmd = Command::new(&cargo);
-    cmd.arg("build");
-    cmd.arg("--message-format").arg("json");
-    let output = cmd
-        .output()
-        .map_err(|err| anyhow!("failed to execute kernel build with json: {}", err))?;
-    if !output.status.success() {
-        return Err(anyhow!("kernel build failed"));
+    if exit_code != 0 {
+        process::exit(exit_code);
     }
-    let mut executables = Vec::new();
+    Ok(())
+}
 
-    match raw_args.next().as_deref() {
-        Some(exe) => executables.push(PathBuf::from(exe)),
-        None => {
-            for line in String::from_utf8(output.stdout)
-                .map_err(|_| anyhow!("Invalid UTF-8"))?
-                .lines()
-            {
-                let mut artifact = json::parse(line).map_err(|_| anyhow!("Invalid JSON"))?;
-                if let Some(executable) = artifact["executable"].take_string() {
-                    executables.push(PathBuf::from(executable));
-                }
-            }
+fn build(args: Args) -> Result<i32> {
+    let mut builder = Builder::new(args.manifest_path.clone())?;
+    let config = load_config(&mut builder, &args)?;
+    let kernel = kernel_executable(&builder, &args)?;
+    let image = builder.create_image(&kernel, &config, Mode::Run, args.entry.as_deref())?;
+    if !args.quiet {
+        println!("Found {}", image.multiboot);
+        if image.direct.is_some() {
+            println!(
+                "Staged kernel for direct boot at `{}`",
+                image.path.display()
+            );
+        } else {
+            println!("Created bootable image at `{}`", image.path.display());
+        }
+        for path in &image.converted {
+            println!("Converted image to `{}`", path.display());
         }
     }
+    Ok(0)
+}
 
-    let cmd = MetadataCommand::new();
-    let metadata = cmd.exec().unwrap();
-    let target = metadata.target_directory;
-    assert!(target.exists());
+fn run(args: Args) -> Result<i32> {
+    let mut builder = Builder::new(args.manifest_path.clone())?;
+    let config = load_config(&mut builder, &args)?;
+    let kernel = kernel_executable(&builder, &args)?;
+    let image = builder.create_image(&kernel, &config, Mode::Run, args.entry.as_deref())?;
+    let platform = &image.platforms[0];
+    run::run(&config, &image, platform, Mode::Run, &args.qemu_args, None)
+}
 
-    let manifest_dir =
-        env::var("CARGO_MANIFEST_DIR").context("Failed to read CARGO_MANIFEST_DIR env var")?;
-    let cargo_toml = Path::new(&manifest_dir).join("Cargo.toml");
-    let is_test = executables[0]
-        .parent()
-        .ok_or_else(|| anyhow!("kernel binary has no parent"))?
-        .ends_with("deps");
+fn test(args: Args) -> Result<i32> {
+    let mut builder = Builder::new(args.manifest_path.clone())?;
+    let config = load_config(&mut builder, &args)?;
+    let executables: Vec<PathBuf> = match &args.executable {
+        Some(exe) => vec![exe.clone()],
+        None => builder
+            .build_kernel(true, &args.cargo_args)?
+            .into_iter()
+            .filter(|exe| exe.mode == Mode::Test)
+            .map(|exe| exe.path)
+            .collect(),
+    };
 
-    let config = config::read_config(&cargo_toml).context("Failed to read configuration")?;
+    // Every executable is run on all test platforms, each run is reported on its own.
+    let mut runs = 0;
+    let mut failed = Vec::new();
+    for executable in &executables {
+        let image = builder.create_image(executable, &config, Mode::Test, args.entry.as_deref())?;
+        for platform in &image.platforms {
+            runs += 1;
+            let name = if image.platforms.len() > 1 {
+                format!("`{}` ({})", executable.display(), platform)
+            } else {
+                format!("`{}`", executable.display())
+            };
+            if !args.quiet {
+                println!("Running {}", name);
+            }
+            let code = run::run(
+                &config,
+                &image,
+                platform,
+                Mode::Test,
+                &args.qemu_args,
+                args.timeout,
+            )
+            .unwrap_or_else(|err| {
+                eprintln!("Error: {:?}", err);
+                1
+            });
+            if code != 0 {
+                failed.push(name);
+            }
+        }
+    }
 
-    let sysroot = target.join("sysroot");
-    let iso_out = target.join("os.iso");
-    let grub_out = sysroot.join("boot/grub");
-    let kernel_out = sysroot.join("boot/kernel.bin");
-    let grub_cfg = grub_out.join("grub.cfg");
+    if !args.quiet {
+        let kind = if runs == executables.len() {
+            "executables"
+        } else {
+            "runs"
+        };
+        println!("{} of {} test {} passed", runs - failed.len(), runs, kind);
+    }
+    for name in &failed {
+        eprintln!("FAILED: {}", name);
+    }
+    Ok(if failed.is_empty() { 0 } else { 1 })
+}
 
-    // Create grub dir and copy executable
-    fs::create_dir_all(grub_out)?;
-    fs::copy(executables[0].to_owned(), kernel_out)?;
+fn runner(args: Args) -> Result<i32> {
+    let mut builder = Builder::new(args.manifest_path.clone())?;
+    let mut config = load_config(&mut builder, &args)?;
+    let executable = args
+        .executable
+        .clone()
+        .ok_or_else(|| anyhow!("`runner` requires an executable"))?;
+    let mode = builder.identify(&executable);
+    config.append_kernel_args(mode, &args.runner_args);
 
-    // Build grub config
-    let mut grub_config = String::new();
+    let image = builder.create_image(&executable, &config, mode, args.entry.as_deref())?;
+    if let [platform] = image.platforms.as_slice() {
+        return run::run(
+            &config,
+            &image,
+            platform,
+            mode,
+            &args.qemu_args,
+            args.timeout,
+        );
+    }
 
-    grub_config.push_str("set timeout=0\n");
-    grub_config.push_str("set default=0\n");
-    grub_config.push_str("menuentry \"My OS\" {\n");
-    grub_config.push_str("\tmultiboot2 /boot/kernel.bin\n");
-    if let Some(modules) = config.modules {
-        for module in modules {
-            let cwd = env::current_dir().context("Cannot access current directory")?;
-            let module_path = cwd.join(PathBuf::from(&module));
-            let grub_module_name = module_path.as_path().file_name().ok_or_else(|| anyhow!("Failed to get file name"))?.to_str();
-            let grub_module_path = grub_module_name.ok_or(anyhow!("Invalid utf-8"))?;
-            fs::copy(&module_path, sysroot.join(grub_module_path.clone())).context("Copying grub module")?;
-            grub_config.push_str(format!("\tmodule2 /{}\n", grub_module_path).as_str());
+    // Tests run on several platforms fail if any of the runs fails.
+    let mut exit_code = 0;
+    for platform in &image.platforms {
+        if !args.quiet {
+            eprintln!("Running on {}", platform);
+        }
+        let code = run::run(
+            &config,
+            &image,
+            platform,
+            mode,
+            &args.qemu_args,
+            args.timeout,
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("Error: {:?}", err);
+            1
+        });
+        if code != 0 {
+            eprintln!("FAILED on {}", platform);
+            if exit_code == 0 {
+                exit_code = code;
+            }
         }
     }
-    grub_config.push_str("\tboot\n}");
-
-    fs::write(grub_cfg, grub_config)?;
-
-    let _output = Command::new("grub-mkrescue")
-        .args(&["-o", iso_out.to_str().unwrap(), sysroot.to_str().unwrap()])
-        .output()
-        .expect("Failed to execute grub-mkrescue");
+    Ok(exit_code)
+}
 
-    let mut extra_args = Vec::new();
-    if is_test {
-        if let Some(args) = config.test_args {
-            extra_args.extend(args);
+fn clean(args: Args) -> Result<i32> {
+    let builder = Builder::new(args.manifest_path.clone())?;
+    let (removed, busy) = builder.clean()?;
+    if !args.quiet {
+        for path in removed {
+            println!("Removed `{}`", path.display());
         }
-    } else if let Some(args) = config.run_args {
-        extra_args.extend(args);
     }
+    for path in busy {
+        eprintln!("Skipped `{}`, it is in use", path.display());
+    }
+    Ok(0)
+}
+
+/// Reads the configuration, applies the overrides given on the command line and in the
+/// environment and makes `builder` run the configured tools.
+fn load_config(builder: &mut Builder, args: &Args) -> Result<Config> {
+    let mut config = builder.config()?;
+    let qemu = env::var(tools::QEMU_ENV_VAR)
+        .ok()
+        .filter(|qemu| !qemu.is_empty());
+    if let Some(binary) = args.qemu_binary.clone().or(qemu) {
+        config.qemu_binary = Some(binary);
+    }
+    builder.set_tools(config.tools.clone());
+    Ok(config)
+}
 
-    let mut output = Command::new("qemu-system-x86_64")
-        .args(&["-cdrom", iso_out.to_str().unwrap()])
-        .args(&extra_args)
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .expect("QEMU system-x86_64 failed");
+/// Reads the configuration of the crate `doctor` is run in, so it checks the configured
+/// tools, together with its manifest directory, which the OVMF paths are relative to. Outside
+/// of a crate the defaults are checked, an invalid configuration is reported as a warning.
+fn doctor_config(args: &Args) -> Result<(Config, PathBuf)> {
+    let builder = match Builder::new(args.manifest_path.clone()) {
+        Ok(builder) => builder,
+        // A manifest given explicitly must exist.
+        Err(err) if args.manifest_path.is_some() => return Err(err),
+        Err(_) => return Ok((Config::new(), PathBuf::from("."))),
+    };
+    let config = builder.config().unwrap_or_else(|err| {
+        eprintln!("Warning: {:#}, checking the default configuration", err);
+        Config::new()
+    });
+    Ok((config, builder.manifest_dir().to_owned()))
+}
 
-    let timeout = Duration::from_secs(config.test_timeout.into());
-    if is_test {
-        match output
-            .wait_timeout(timeout)
-            .context("Failed to wait with timeout")?
-        {
-            Some(exit_code) => {
-                if config.test_success_exit_code.unwrap_or(0)
-                    != exit_code.code().unwrap_or_else(|| 0)
-                {
-                    std::process::exit(exit_code.code().unwrap_or_else(|| 0));
-                }
-            }
-            None => {
-                output.kill().context("Failed to kill QEMU")?;
-                output.wait().context("Failed to wait for QEMU process")?;
-                return Err(anyhow!("Test timed out"));
-            }
+/// Returns the executable given on the command line, or builds the kernel with cargo.
+fn kernel_executable(builder: &Builder, args: &Args) -> Result<PathBuf> {
+    if let Some(executable) = &args.executable {
+        return Ok(executable.clone());
+    }
+    let executables: Vec<PathBuf> = builder
+        .build_kernel(false, &args.cargo_args)?
+        .into_iter()
+        .filter(|exe| exe.mode == Mode::Run)
+        .map(|exe| exe.path)
+        .collect();
+    match executables.as_slice() {
+        [executable] => Ok(executable.clone()),
+        [] => Err(anyhow!("cargo build did not produce an executable")),
+        [first, ..] => {
+            eprintln!(
+                "Warning: cargo build produced {} executables, using `{}`",
+                executables.len(),
+                first.display()
+            );
+            Ok(first.clone())
         }
     }
-
-    Ok(())
 }
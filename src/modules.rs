@@ -0,0 +1,215 @@
+use crate::{
+    archive::{self, ArchiveFormat},
+    builder::Builder,
+    config::{Module, ModuleSource},
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A module whose contents have been located, ready to be installed into the sysroot.
+#[derive(Debug, Clone)]
+pub struct ResolvedModule {
+    pub content: Content,
+    /// Absolute path of the module in the image.
+    pub dest: String,
+    /// The command line string of the module.
+    pub args: Option<String>,
+}
+
+/// The contents of a resolved module.
+#[derive(Debug, Clone)]
+pub enum Content {
+    /// A file copied into the image.
+    File(PathBuf),
+    /// An archive of a directory, created in the image.
+    Archive { dir: PathBuf, format: ArchiveFormat },
+}
+
+impl Content {
+    /// The path the module is created from, for messages.
+    pub fn path(&self) -> &Path {
+        match self {
+            Content::File(path) => path,
+            Content::Archive { dir, .. } => dir,
+        }
+    }
+
+    /// The default file name of the module in the image.
+    fn file_name(&self) -> Result<String> {
+        let name = self
+            .path()
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Invalid module path `{}`", self.path().display()))?;
+        Ok(match self {
+            Content::File(_) => name.to_owned(),
+            Content::Archive { format, .. } => format!("{}.{}", name, format.extension()),
+        })
+    }
+}
+
+impl ResolvedModule {
+    /// Copies or creates the module at its destination below `sysroot`.
+    pub fn install(&self, sysroot: &Path) -> Result<()> {
+        let dest_path = sysroot.join(self.dest.trim_start_matches('/'));
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match &self.content {
+            Content::File(source) => fs::copy(source, &dest_path)
+                .map(|_| ())
+                .with_context(|| format!("Failed to copy module `{}`", source.display())),
+            Content::Archive { dir, format } => archive::write_archive(dir, *format, &dest_path),
+        }
+    }
+}
+
+/// Locates the files of `modules`, building the workspace packages among them.
+///
+/// Paths are relative to the directory of the manifest declaring the modules, so the result
+/// does not depend on the directory cargo is run from. Paths may be glob patterns like
+/// `assets/*.elf`.
+pub fn resolve(modules: &[Module], builder: &Builder) -> Result<Vec<ResolvedModule>> {
+    let base = builder.manifest_dir();
+    let mut resolved: Vec<ResolvedModule> = Vec::new();
+    for module in modules {
+        let contents = match &module.source {
+            ModuleSource::Path(pattern) => locate(pattern, base)?
+                .into_iter()
+                .map(Content::File)
+                .collect(),
+            ModuleSource::Archive { dir, format } => {
+                let dir = base.join(dir);
+                if !dir.is_dir() {
+                    return Err(anyhow!(
+                        "archive directory `{}` not found (module paths are relative to the \
+                         directory of Cargo.toml)",
+                        dir.display()
+                    ));
+                }
+                vec![Content::Archive {
+                    dir,
+                    format: *format,
+                }]
+            }
+            ModuleSource::Package(package) => vec![Content::File(builder.build_package(package)?)],
+        };
+        for content in contents {
+            let dest = dest(module, &content)?;
+            check_collision(&resolved, &content, &dest)?;
+            resolved.push(ResolvedModule {
+                content,
+                dest,
+                args: module.args.clone(),
+            });
+        }
+    }
+    Ok(resolved)
+}
+
+/// Adds the modules of `more` to `modules`, skipping the ones that are already in it.
+///
+/// Modules are the same if they are created from the same path at the same destination, so
+/// modules shared by several menu entries are only installed once.
+pub fn merge(modules: &mut Vec<ResolvedModule>, more: &[ResolvedModule]) -> Result<()> {
+    for module in more {
+        let duplicate = modules.iter().any(|other| {
+            other.dest == module.dest && other.content.path() == module.content.path()
+        });
+        if !duplicate {
+            check_collision(modules, &module.content, &module.dest)?;
+            modules.push(module.clone());
+        }
+    }
+    Ok(())
+}
+
+fn check_collision(modules: &[ResolvedModule], content: &Content, dest: &str) -> Result<()> {
+    match modules.iter().find(|other| other.dest == dest) {
+        Some(other) => Err(anyhow!(
+            "modules `{}` and `{}` would both be copied to `{}`, set `dest` for one of them",
+            other.content.path().display(),
+            content.path().display(),
+            dest
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Returns the files matching the module path or glob `pattern`.
+fn locate(pattern: &str, base: &Path) -> Result<Vec<PathBuf>> {
+    let path = base.join(pattern);
+    let is_glob = pattern.contains(['*', '?', '[']);
+    if !is_glob {
+        if path.is_file() {
+            return Ok(vec![path]);
+        }
+        let mut message = format!(
+            "module `{}` not found, searched `{}` (module paths are relative to the \
+             directory of Cargo.toml)",
+            pattern,
+            path.display()
+        );
+        if Path::new(pattern).is_file() {
+            message.push_str(&format!(
+                "; `{}` exists relative to the current directory",
+                pattern
+            ));
+        }
+        return Err(anyhow!(message));
+    }
+
+    // Only the pattern is a glob, brackets or stars in the manifest directory are literal.
+    let base = base
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid module path `{}`", path.display()))?;
+    let glob_path = Path::new(&glob::Pattern::escape(base)).join(pattern);
+    let glob_path = glob_path
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid module path `{}`", path.display()))?;
+    let mut files = Vec::new();
+    for entry in
+        glob::glob(glob_path).with_context(|| format!("Invalid module pattern `{}`", pattern))?
+    {
+        let entry = entry.with_context(|| format!("Failed to expand `{}`", pattern))?;
+        if entry.is_file() {
+            files.push(entry);
+        }
+    }
+    if files.is_empty() {
+        return Err(anyhow!(
+            "module pattern `{}` matched no files, searched `{}`",
+            pattern,
+            path.display()
+        ));
+    }
+    Ok(files)
+}
+
+/// Returns the absolute path of `content` in the image.
+///
+/// Without `dest` modules are placed in the root directory; a `dest` ending with `/` is a
+/// directory the module is placed in, which is how glob patterns are given a destination.
+fn dest(module: &Module, content: &Content) -> Result<String> {
+    let file_name = content.file_name()?;
+    let dest = match module.dest.as_deref() {
+        Some(dir) if dir.ends_with('/') => format!("{}{}", dir, file_name),
+        Some(dest) => dest.to_owned(),
+        None => file_name.to_owned(),
+    };
+    let dest = format!("/{}", dest.trim_start_matches('/'));
+    if dest.contains(char::is_whitespace) || dest.split('/').any(|part| part == "..") {
+        return Err(anyhow!("Invalid module destination `{}`", dest));
+    }
+    if dest == "/boot/kernel.bin" || dest.starts_with("/boot/grub/") {
+        return Err(anyhow!(
+            "module `{}` would overwrite `{}`, which is used by grub-bootimage",
+            content.path().display(),
+            dest
+        ));
+    }
+    Ok(dest)
+}
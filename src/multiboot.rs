@@ -0,0 +1,702 @@
+use crate::elf::{self, ElfClass, ElfHeader};
+use anyhow::{anyhow, Context, Result};
+use std::{convert::TryInto, fmt, fs, path::Path};
+
+/// The magic value at the start of a multiboot header.
+const MULTIBOOT1_MAGIC: u32 = 0x1BAD_B002;
+/// Multiboot loaders only search this many bytes at the start of the kernel for the header.
+const MULTIBOOT1_SEARCH_LIMIT: usize = 8 * 1024;
+/// The multiboot header must be 32-bit aligned.
+const MULTIBOOT1_ALIGNMENT: usize = 4;
+/// Flag of a multiboot header whose address fields tell where to load the kernel.
+const MULTIBOOT1_FLAG_ADDRESS: u32 = 1 << 16;
+
+/// The magic value at the start of a multiboot2 header.
+const MULTIBOOT2_MAGIC: u32 = 0xE852_50D6;
+/// GRUB only searches this many bytes at the start of the kernel for the header.
+const SEARCH_LIMIT: usize = 32 * 1024;
+/// The header must be 64-bit aligned.
+const ALIGNMENT: usize = 8;
+
+/// `architecture` of a multiboot2 header for 32-bit protected mode i386.
+const ARCH_I386: u32 = 0;
+/// `architecture` of a multiboot2 header for 32-bit MIPS.
+const ARCH_MIPS32: u32 = 4;
+
+/// Type of the tag terminating the list of tags.
+const TAG_END: u16 = 0;
+/// Type of the address tag, which is required for non-ELF kernels.
+const TAG_ADDRESS: u16 = 2;
+
+/// The boot protocol GRUB loads the kernel with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Multiboot version 1, loaded with GRUB's `multiboot` and `module` commands.
+    Multiboot,
+    /// Multiboot2, loaded with GRUB's `multiboot2` and `module2` commands.
+    Multiboot2,
+}
+
+impl Protocol {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "multiboot" | "multiboot1" => Ok(Protocol::Multiboot),
+            "multiboot2" => Ok(Protocol::Multiboot2),
+            other => Err(anyhow!(
+                "unknown protocol `{}` (expected `multiboot` or `multiboot2`)",
+                other
+            )),
+        }
+    }
+
+    /// The GRUB command loading the kernel.
+    pub fn kernel_command(self) -> &'static str {
+        match self {
+            Protocol::Multiboot => "multiboot",
+            Protocol::Multiboot2 => "multiboot2",
+        }
+    }
+
+    /// The GRUB command loading a module.
+    pub fn module_command(self) -> &'static str {
+        match self {
+            Protocol::Multiboot => "module",
+            Protocol::Multiboot2 => "module2",
+        }
+    }
+}
+
+/// The validated multiboot header of a kernel, of the version it is booted with.
+#[derive(Debug, Clone)]
+pub enum MultibootHeader {
+    V1(Multiboot1Header),
+    V2(Multiboot2Header),
+}
+
+impl MultibootHeader {
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            MultibootHeader::V1(_) => Protocol::Multiboot,
+            MultibootHeader::V2(_) => Protocol::Multiboot2,
+        }
+    }
+
+    /// The ELF header of the kernel, `None` for a.out kludge kernels.
+    pub fn elf(&self) -> Option<&ElfHeader> {
+        match self {
+            MultibootHeader::V1(header) => header.elf.as_ref(),
+            MultibootHeader::V2(header) => header.elf.as_ref(),
+        }
+    }
+}
+
+impl fmt::Display for MultibootHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultibootHeader::V1(header) => header.fmt(f),
+            MultibootHeader::V2(header) => header.fmt(f),
+        }
+    }
+}
+
+/// A validated multiboot (version 1) header.
+#[derive(Debug, Clone)]
+pub struct Multiboot1Header {
+    /// Offset of the header from the start of the kernel file.
+    pub offset: usize,
+    pub flags: u32,
+    /// The ELF header of the kernel, `None` for a.out kludge kernels.
+    pub elf: Option<ElfHeader>,
+}
+
+impl Multiboot1Header {
+    /// Whether the header contains the addresses to load the kernel at.
+    ///
+    /// Loaders use them instead of the ELF program headers if present.
+    pub fn has_address(&self) -> bool {
+        self.flags & MULTIBOOT1_FLAG_ADDRESS != 0
+    }
+}
+
+impl fmt::Display for Multiboot1Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "multiboot header at offset {:#x} (flags {:#x}",
+            self.offset, self.flags
+        )?;
+        if let Some(elf) = &self.elf {
+            write!(f, ", {} {}", class_name(elf.class), elf.machine_name())?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// A validated multiboot2 header.
+#[derive(Debug, Clone)]
+pub struct Multiboot2Header {
+    /// Offset of the header from the start of the kernel file.
+    pub offset: usize,
+    pub architecture: u32,
+    pub header_length: u32,
+    /// The types of the tags in the header, without the end tag.
+    pub tags: Vec<u16>,
+    /// The ELF header of the kernel, `None` for a.out kludge kernels.
+    pub elf: Option<ElfHeader>,
+}
+
+impl fmt::Display for Multiboot2Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let architecture = match self.architecture {
+            ARCH_I386 => "i386",
+            ARCH_MIPS32 => "MIPS32",
+            _ => "unknown",
+        };
+        write!(
+            f,
+            "multiboot2 header at offset {:#x} ({}, {} bytes",
+            self.offset, architecture, self.header_length
+        )?;
+        if let Some(elf) = &self.elf {
+            write!(f, ", {} {}", class_name(elf.class), elf.machine_name())?;
+        }
+        write!(f, ")")?;
+        if self.tags.is_empty() {
+            write!(f, " without tags")
+        } else {
+            let tags: Vec<_> = self
+                .tags
+                .iter()
+                .map(|&tag| match tag_name(tag) {
+                    "unknown" => format!("unknown ({})", tag),
+                    name => name.to_owned(),
+                })
+                .collect();
+            write!(f, " with tags: {}", tags.join(", "))
+        }
+    }
+}
+
+/// Reads the kernel at `path` and validates its header for `protocol`.
+///
+/// Without a protocol, the kernel is booted with multiboot2 unless it only has a multiboot
+/// header, so kernels carrying both headers get the more detailed boot information.
+pub fn read_header(path: &Path, protocol: Option<Protocol>) -> Result<MultibootHeader> {
+    let data =
+        fs::read(path).with_context(|| format!("Failed to read kernel `{}`", path.display()))?;
+    let protocol = match protocol {
+        Some(protocol) => protocol,
+        None => match (
+            find_magic(&data, MULTIBOOT2_MAGIC, 4),
+            find_magic(&data, MULTIBOOT1_MAGIC, MULTIBOOT1_ALIGNMENT),
+        ) {
+            (None, Some(_)) => Protocol::Multiboot,
+            (Some(_), _) => Protocol::Multiboot2,
+            (None, None) => {
+                return Err(anyhow!(
+                    "Invalid kernel `{}`: no multiboot2 or multiboot header found",
+                    path.display()
+                ))
+            }
+        },
+    };
+    match protocol {
+        Protocol::Multiboot => parse_multiboot1_header(&data)
+            .map(MultibootHeader::V1)
+            .with_context(|| format!("Invalid multiboot kernel `{}`", path.display())),
+        Protocol::Multiboot2 => parse_multiboot2_header(&data)
+            .map(MultibootHeader::V2)
+            .with_context(|| format!("Invalid multiboot2 kernel `{}`", path.display())),
+    }
+}
+
+/// Reads the kernel at `path` and validates its multiboot header.
+pub fn read_multiboot1_header(path: &Path) -> Result<Multiboot1Header> {
+    let data =
+        fs::read(path).with_context(|| format!("Failed to read kernel `{}`", path.display()))?;
+    parse_multiboot1_header(&data)
+        .with_context(|| format!("Invalid multiboot kernel `{}`", path.display()))
+}
+
+fn parse_multiboot1_header(data: &[u8]) -> Result<Multiboot1Header> {
+    let elf = if elf::is_elf(data) {
+        Some(elf::parse_header(data)?)
+    } else {
+        None
+    };
+
+    let searched = &data[..data.len().min(MULTIBOOT1_SEARCH_LIMIT)];
+    let offset = match find_magic(searched, MULTIBOOT1_MAGIC, MULTIBOOT1_ALIGNMENT) {
+        Some(offset) => offset,
+        None => {
+            return Err(
+                match find_magic(data, MULTIBOOT1_MAGIC, MULTIBOOT1_ALIGNMENT) {
+                    Some(offset) => anyhow!(
+                        "the multiboot header is at offset {:#x}, but loaders only search the \
+                     first 8 KiB; place it in a section at the start of the kernel",
+                        offset
+                    ),
+                    None => anyhow!("no multiboot header found in the first 8 KiB"),
+                },
+            );
+        }
+    };
+
+    let field = |index: usize| read_u32(data, offset + 4 * index);
+    let (flags, checksum) = match (field(1), field(2)) {
+        (Some(flags), Some(checksum)) => (flags, checksum),
+        _ => {
+            return Err(anyhow!(
+                "the multiboot header at {:#x} is truncated",
+                offset
+            ))
+        }
+    };
+    let expected = 0u32.wrapping_sub(MULTIBOOT1_MAGIC.wrapping_add(flags));
+    if checksum != expected {
+        return Err(anyhow!(
+            "the multiboot header checksum is {:#010x}, expected {:#010x}",
+            checksum,
+            expected
+        ));
+    }
+
+    if flags & MULTIBOOT1_FLAG_ADDRESS != 0 {
+        // header_addr, load_addr, load_end_addr, bss_end_addr and entry_addr
+        if field(7).is_none() {
+            return Err(anyhow!(
+                "the multiboot header at {:#x} is truncated, its address fields are missing",
+                offset
+            ));
+        }
+    } else if elf.is_none() {
+        return Err(anyhow!(
+            "the kernel is not an ELF file, so its multiboot header needs the address flag \
+             (bit 16) and address fields"
+        ));
+    }
+
+    Ok(Multiboot1Header { offset, flags, elf })
+}
+
+/// Validates the multiboot2 header of the kernel in `data`.
+///
+/// This catches the mistakes that otherwise only show up as "no multiboot header found" in
+/// GRUB: a header that is missing, misaligned, too far into the file or has a bad checksum.
+fn parse_multiboot2_header(data: &[u8]) -> Result<Multiboot2Header> {
+    let elf = if elf::is_elf(data) {
+        Some(elf::parse_header(data)?)
+    } else {
+        None
+    };
+
+    let searched = &data[..data.len().min(SEARCH_LIMIT)];
+    let offset = match find_magic(searched, MULTIBOOT2_MAGIC, ALIGNMENT) {
+        Some(offset) => offset,
+        None => {
+            return Err(match find_magic(data, MULTIBOOT2_MAGIC, 4) {
+                Some(offset) if offset >= SEARCH_LIMIT => anyhow!(
+                    "the multiboot2 header is at offset {:#x}, but GRUB only searches the \
+                     first 32 KiB; place it in a section at the start of the kernel",
+                    offset
+                ),
+                Some(offset) => anyhow!(
+                    "the multiboot2 header at offset {:#x} is not 8-byte aligned",
+                    offset
+                ),
+                None => anyhow!("no multiboot2 header found in the first 32 KiB"),
+            });
+        }
+    };
+
+    let field = |index: usize| read_u32(data, offset + 4 * index);
+    let (architecture, header_length, checksum) = match (field(1), field(2), field(3)) {
+        (Some(architecture), Some(length), Some(checksum)) => (architecture, length, checksum),
+        _ => {
+            return Err(anyhow!(
+                "the multiboot2 header at {:#x} is truncated",
+                offset
+            ))
+        }
+    };
+
+    match (architecture, elf.map(|elf| elf.machine)) {
+        (ARCH_I386, None | Some(elf::EM_386) | Some(elf::EM_X86_64)) => {}
+        (ARCH_MIPS32, None | Some(elf::EM_MIPS)) => {}
+        (ARCH_I386 | ARCH_MIPS32, Some(_)) => {
+            return Err(anyhow!(
+                "the multiboot2 header architecture {} does not match the {} kernel",
+                architecture,
+                elf.map(|elf| elf.machine_name()).unwrap_or_default()
+            ))
+        }
+        _ => {
+            return Err(anyhow!(
+                "unknown multiboot2 architecture {} (expected 0 for i386 or 4 for MIPS)",
+                architecture
+            ))
+        }
+    }
+
+    let length = header_length as usize;
+    if length < 16 {
+        return Err(anyhow!(
+            "the multiboot2 header length {} is smaller than the 16 byte header",
+            header_length
+        ));
+    }
+    if offset + length > SEARCH_LIMIT || offset + length > data.len() {
+        return Err(anyhow!(
+            "the multiboot2 header at {:#x} with length {} extends beyond the first 32 KiB \
+             or the end of the kernel",
+            offset,
+            header_length
+        ));
+    }
+
+    let expected = 0u32.wrapping_sub(
+        MULTIBOOT2_MAGIC
+            .wrapping_add(architecture)
+            .wrapping_add(header_length),
+    );
+    if checksum != expected {
+        return Err(anyhow!(
+            "the multiboot2 header checksum is {:#010x}, expected {:#010x}",
+            checksum,
+            expected
+        ));
+    }
+
+    let tags = parse_tags(&data[offset + 16..offset + length], offset + 16)?;
+    if elf.is_none() && !tags.contains(&TAG_ADDRESS) {
+        return Err(anyhow!(
+            "the kernel is not an ELF file, so its multiboot2 header needs an address tag"
+        ));
+    }
+
+    Ok(Multiboot2Header {
+        offset,
+        architecture,
+        header_length,
+        tags,
+        elf,
+    })
+}
+
+/// Parses the tags following the fixed part of the header, `base` is their file offset.
+fn parse_tags(data: &[u8], base: usize) -> Result<Vec<u16>> {
+    let mut tags = Vec::new();
+    let mut offset = 0;
+    loop {
+        let (tag, size) = match (read_u32(data, offset), read_u32(data, offset + 4)) {
+            (Some(tag), Some(size)) => ((tag & 0xffff) as u16, size as usize),
+            _ => {
+                return Err(anyhow!(
+                    "the multiboot2 header has no end tag within its header length"
+                ))
+            }
+        };
+        if size < 8 || offset + size > data.len() {
+            return Err(anyhow!(
+                "the multiboot2 tag {} at {:#x} has an invalid size {}",
+                tag_name(tag),
+                base + offset,
+                size
+            ));
+        }
+        if tag == TAG_END {
+            return Ok(tags);
+        }
+        tags.push(tag);
+        // Tags are padded to 8 bytes.
+        offset += size.div_ceil(ALIGNMENT) * ALIGNMENT;
+    }
+}
+
+/// Returns the offset of the first `magic` aligned to `alignment`.
+fn find_magic(data: &[u8], magic: u32, alignment: usize) -> Option<usize> {
+    (0..data.len())
+        .step_by(alignment)
+        .find(|&offset| read_u32(data, offset) == Some(magic))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn class_name(class: ElfClass) -> &'static str {
+    match class {
+        ElfClass::Elf32 => "ELF32",
+        ElfClass::Elf64 => "ELF64",
+    }
+}
+
+fn tag_name(tag: u16) -> &'static str {
+    match tag {
+        0 => "end",
+        1 => "information request",
+        2 => "address",
+        3 => "entry address",
+        4 => "console flags",
+        5 => "framebuffer",
+        6 => "module alignment",
+        7 => "EFI boot services",
+        8 => "EFI i386 entry address",
+        9 => "EFI amd64 entry address",
+        10 => "relocatable",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ELF header for `machine` at the start of a kernel.
+    fn elf(class: ElfClass, machine: u16) -> Vec<u8> {
+        let mut data = vec![0; 0x40];
+        data[..4].copy_from_slice(b"\x7fELF");
+        data[4] = match class {
+            ElfClass::Elf32 => 1,
+            ElfClass::Elf64 => 2,
+        };
+        data[5] = 1;
+        data[0x12..0x14].copy_from_slice(&machine.to_le_bytes());
+        data
+    }
+
+    /// A multiboot2 tag of `size` bytes, padded to 8 bytes.
+    fn tag(tag: u16, size: u32) -> Vec<u8> {
+        let mut data = vec![0; (size as usize).div_ceil(ALIGNMENT) * ALIGNMENT];
+        data[..2].copy_from_slice(&tag.to_le_bytes());
+        data[4..8].copy_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    /// A multiboot2 header with `tags` and a valid checksum.
+    fn multiboot2(architecture: u32, tags: &[Vec<u8>]) -> Vec<u8> {
+        let tags = tags.concat();
+        let length = 16 + tags.len() as u32;
+        let checksum = 0u32.wrapping_sub(
+            MULTIBOOT2_MAGIC
+                .wrapping_add(architecture)
+                .wrapping_add(length),
+        );
+        let mut data = Vec::new();
+        for field in &[MULTIBOOT2_MAGIC, architecture, length, checksum] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(&tags);
+        data
+    }
+
+    /// A multiboot header with `flags`, a valid checksum and zeroed address fields if the
+    /// address flag is set.
+    fn multiboot1(flags: u32) -> Vec<u8> {
+        let checksum = 0u32.wrapping_sub(MULTIBOOT1_MAGIC.wrapping_add(flags));
+        let mut data = Vec::new();
+        for field in &[MULTIBOOT1_MAGIC, flags, checksum] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        if flags & MULTIBOOT1_FLAG_ADDRESS != 0 {
+            data.resize(32, 0);
+        }
+        data
+    }
+
+    /// A kernel starting with `prefix` and containing `header` at `offset`.
+    fn kernel(prefix: &[u8], offset: usize, header: &[u8]) -> Vec<u8> {
+        let mut data = prefix.to_vec();
+        data.resize(offset, 0);
+        data.extend_from_slice(header);
+        data.resize(data.len() + 64, 0);
+        data
+    }
+
+    fn elf64() -> Vec<u8> {
+        elf(ElfClass::Elf64, elf::EM_X86_64)
+    }
+
+    fn end() -> Vec<u8> {
+        tag(TAG_END, 8)
+    }
+
+    #[test]
+    fn valid_multiboot2_headers() {
+        let header = parse_multiboot2_header(&kernel(
+            &elf64(),
+            0x1000,
+            &multiboot2(ARCH_I386, &[tag(1, 12), end()]),
+        ))
+        .unwrap();
+        assert_eq!(header.offset, 0x1000);
+        assert_eq!(header.header_length, 16 + 16 + 8);
+        assert_eq!(header.tags, vec![1]);
+        assert_eq!(header.elf.unwrap().class, ElfClass::Elf64);
+
+        let header = parse_multiboot2_header(&kernel(
+            &[],
+            0,
+            &multiboot2(ARCH_I386, &[tag(TAG_ADDRESS, 24), end()]),
+        ))
+        .unwrap();
+        assert!(header.elf.is_none());
+        assert_eq!(header.tags, vec![TAG_ADDRESS]);
+    }
+
+    #[test]
+    fn invalid_multiboot2_headers() {
+        let mut bad_checksum = multiboot2(ARCH_I386, &[end()]);
+        bad_checksum[12] ^= 1;
+        let mut too_long = multiboot2(ARCH_I386, &[end()]);
+        too_long[8..12].copy_from_slice(&0x100u32.to_le_bytes());
+        let cases: &[(&str, Vec<u8>, &str)] = &[
+            (
+                "missing",
+                kernel(&elf64(), 0x1000, &[]),
+                "no multiboot2 header found in the first 32 KiB",
+            ),
+            (
+                "misaligned",
+                kernel(&elf64(), 0x1004, &multiboot2(ARCH_I386, &[end()])),
+                "at offset 0x1004 is not 8-byte aligned",
+            ),
+            (
+                "beyond 32 KiB",
+                kernel(&elf64(), 0x8000, &multiboot2(ARCH_I386, &[end()])),
+                "is at offset 0x8000, but GRUB only searches the first 32 KiB",
+            ),
+            (
+                "extends beyond 32 KiB",
+                kernel(&elf64(), 0x7ff8, &multiboot2(ARCH_I386, &[end()])),
+                "extends beyond the first 32 KiB",
+            ),
+            (
+                "bad checksum",
+                kernel(&elf64(), 0x1000, &bad_checksum),
+                "checksum is",
+            ),
+            (
+                "architecture mismatch",
+                kernel(&elf64(), 0x1000, &multiboot2(ARCH_MIPS32, &[end()])),
+                "architecture 4 does not match the x86_64 kernel",
+            ),
+            (
+                "unknown architecture",
+                kernel(&elf64(), 0x1000, &multiboot2(7, &[end()])),
+                "unknown multiboot2 architecture 7",
+            ),
+            (
+                "length beyond the kernel",
+                too_long,
+                "extends beyond the first 32 KiB or the end of the kernel",
+            ),
+            (
+                "truncated tag",
+                kernel(
+                    &elf64(),
+                    0x1000,
+                    &multiboot2(ARCH_I386, &[tag(5, 20)[..8].to_vec()]),
+                ),
+                "tag framebuffer at 0x1010 has an invalid size 20",
+            ),
+            (
+                "no end tag",
+                kernel(&elf64(), 0x1000, &multiboot2(ARCH_I386, &[tag(1, 8)])),
+                "no end tag",
+            ),
+            (
+                "non-ELF without address tag",
+                kernel(&[], 0, &multiboot2(ARCH_I386, &[end()])),
+                "needs an address tag",
+            ),
+        ];
+        for (name, data, expected) in cases {
+            match parse_multiboot2_header(data) {
+                Ok(header) => panic!("{}: accepted {}", name, header),
+                Err(error) => assert!(
+                    error.to_string().contains(expected),
+                    "{}: `{}` does not contain `{}`",
+                    name,
+                    error,
+                    expected
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn valid_multiboot1_headers() {
+        let header = parse_multiboot1_header(&kernel(
+            &elf(ElfClass::Elf32, elf::EM_386),
+            0x40,
+            &multiboot1(3),
+        ))
+        .unwrap();
+        assert_eq!(header.offset, 0x40);
+        assert!(!header.has_address());
+        assert_eq!(header.elf.unwrap().class, ElfClass::Elf32);
+
+        let header =
+            parse_multiboot1_header(&kernel(&[], 0x10, &multiboot1(MULTIBOOT1_FLAG_ADDRESS)))
+                .unwrap();
+        assert!(header.has_address());
+        assert!(header.elf.is_none());
+    }
+
+    #[test]
+    fn invalid_multiboot1_headers() {
+        let mut bad_checksum = multiboot1(0);
+        bad_checksum[8] ^= 1;
+        let cases: &[(&str, Vec<u8>, &str)] = &[
+            (
+                "missing",
+                kernel(&elf64(), 0x1000, &[]),
+                "no multiboot header found in the first 8 KiB",
+            ),
+            (
+                "misaligned",
+                kernel(&elf64(), 0x1002, &multiboot1(0)),
+                "no multiboot header found in the first 8 KiB",
+            ),
+            (
+                "beyond 8 KiB",
+                kernel(&elf64(), 0x2000, &multiboot1(0)),
+                "is at offset 0x2000, but loaders only search the first 8 KiB",
+            ),
+            (
+                "truncated",
+                multiboot1(0)[..8].to_vec(),
+                "the multiboot header at 0x0 is truncated",
+            ),
+            (
+                "bad checksum",
+                kernel(&elf64(), 0x1000, &bad_checksum),
+                "checksum is",
+            ),
+            (
+                "truncated address fields",
+                multiboot1(MULTIBOOT1_FLAG_ADDRESS)[..16].to_vec(),
+                "its address fields are missing",
+            ),
+            (
+                "non-ELF without address flag",
+                kernel(&[], 0, &multiboot1(0)),
+                "needs the address flag",
+            ),
+        ];
+        for (name, data, expected) in cases {
+            match parse_multiboot1_header(data) {
+                Ok(header) => panic!("{}: accepted {}", name, header),
+                Err(error) => assert!(
+                    error.to_string().contains(expected),
+                    "{}: `{}` does not contain `{}`",
+                    name,
+                    error,
+                    expected
+                ),
+            }
+        }
+    }
+}
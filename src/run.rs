@@ -0,0 +1,460 @@
+use crate::{
+    builder::BootImage,
+    config::{Config, ExitDevice},
+    firmware::Platform,
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    io::{self, Read, Write},
+    process::{Child, ChildStdout, Command, ExitStatus, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use wait_timeout::ChildExt;
+
+/// How the kernel is run in QEMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Boot the kernel with `run-args` and wait for QEMU to exit.
+    Run,
+    /// Boot the kernel with `test-args` and check its exit code.
+    Test,
+    /// Boot the kernel with `bench-args` and check its exit code.
+    Bench,
+}
+
+/// The `qemu-binary` choosing the emulator from the ELF header of the kernel, the default.
+pub const AUTO_QEMU: &str = "auto";
+
+/// Boots `image` on `platform` in QEMU and returns the exit code the tool should exit with.
+pub fn run(
+    config: &Config,
+    image: &BootImage,
+    platform: &Platform,
+    mode: Mode,
+    extra_args: &[String],
+    timeout: Option<u32>,
+) -> Result<i32> {
+    let mut args = Vec::new();
+    match mode {
+        Mode::Test => {
+            if let Some(test_args) = &config.test_args {
+                args.extend(test_args.iter().cloned());
+            }
+        }
+        Mode::Bench => {
+            if let Some(bench_args) = &config.bench_args {
+                args.extend(bench_args.iter().cloned());
+            }
+        }
+        Mode::Run => {
+            if let Some(run_args) = &config.run_args {
+                args.extend(run_args.iter().cloned());
+            }
+        }
+    }
+    args.extend(extra_args.iter().cloned());
+    if let (Mode::Test | Mode::Bench, Some(device)) = (mode, &config.test_exit_device) {
+        // A device added by hand in the QEMU arguments would conflict with a second one.
+        if !args.iter().any(|arg| arg.starts_with("isa-debug-exit")) {
+            args.push("-device".to_owned());
+            args.push(format!(
+                "isa-debug-exit,iobase={:#x},iosize={:#04x}",
+                device.iobase, device.iosize
+            ));
+        }
+    }
+
+    // GRUB's errors are only visible on the serial console when running headless, so they
+    // are watched for to fail fast instead of waiting for the timeout.
+    let watch_serial = mode != Mode::Run && config.grub_serial.is_some() && image.direct.is_none();
+    let qemu = match config.qemu_binary.as_deref() {
+        Some(AUTO_QEMU) | None => image.qemu_system(platform),
+        Some(binary) => binary,
+    };
+    let mut child = Command::new(qemu)
+        .args(image.platform_args(platform)?)
+        .args(image.boot_args()?)
+        .args(&args)
+        .stdin(Stdio::inherit())
+        .stdout(if watch_serial {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("Failed to launch {}", qemu))?;
+    let watcher = child.stdout.take().map(SerialWatcher::spawn);
+
+    let timeout = match mode {
+        Mode::Run => {
+            let status = child.wait().context("Failed to wait for QEMU process")?;
+            return Ok(status.code().unwrap_or(1));
+        }
+        Mode::Test => Some(timeout.unwrap_or(config.test_timeout)),
+        Mode::Bench => timeout.or(config.bench_timeout),
+    };
+    let timeout = timeout.map(|timeout| Duration::from_secs(timeout.into()));
+
+    let status = match watcher {
+        Some(watcher) => match watcher.wait(&mut child, timeout)? {
+            Some(status) => status,
+            None => return Err(anyhow!("Test timed out")),
+        },
+        None => match timeout {
+            Some(timeout) => match child
+                .wait_timeout(timeout)
+                .context("Failed to wait with timeout")?
+            {
+                Some(status) => status,
+                None => {
+                    kill(&mut child)?;
+                    return Err(anyhow!("Test timed out"));
+                }
+            },
+            None => child.wait().context("Failed to wait for QEMU process")?,
+        },
+    };
+
+    let code = status.code().unwrap_or(0);
+    if let Some(device) = &config.test_exit_device {
+        return Ok(check_exit_device(device, code));
+    }
+    if config.test_success_exit_code.unwrap_or(0) == code {
+        Ok(0)
+    } else if code == 0 {
+        // A clean QEMU exit is a failure if another code means success.
+        Ok(1)
+    } else {
+        Ok(code)
+    }
+}
+
+/// Translates the exit code of QEMU back to the value the kernel wrote to the exit device and
+/// reports a failure, returns the exit code the tool should exit with.
+fn check_exit_device(device: &ExitDevice, code: i32) -> i32 {
+    // QEMU exits with `(value << 1) | 1`, an even code did not come from the device.
+    if code & 1 == 0 {
+        eprintln!(
+            "QEMU exited with code {} without the kernel writing to the exit device",
+            code
+        );
+        return 1;
+    }
+    let value = code >> 1;
+    if value == i32::from(device.success) {
+        0
+    } else if value == i32::from(device.failure) {
+        eprintln!("Kernel reported failure code {:#x}", value);
+        1
+    } else if value == 0 {
+        // QEMU itself also exits with 1 when it fails.
+        eprintln!("QEMU failed, or the kernel reported code 0x0");
+        1
+    } else {
+        eprintln!(
+            "Kernel reported unknown code {:#x} (success is {:#x}, failure {:#x})",
+            value, device.success, device.failure
+        );
+        1
+    }
+}
+
+fn kill(child: &mut Child) -> Result<()> {
+    child.kill().context("Failed to kill QEMU")?;
+    child.wait().context("Failed to wait for QEMU process")?;
+    Ok(())
+}
+
+/// Forwards the serial output of QEMU to stdout and reports the errors GRUB prints.
+struct SerialWatcher {
+    errors: Receiver<String>,
+    thread: JoinHandle<()>,
+}
+
+impl SerialWatcher {
+    fn spawn(mut stdout: ChildStdout) -> SerialWatcher {
+        let (sender, errors) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            let mut line = Vec::new();
+            let mut stage = Stage::Grub;
+            while let Ok(len @ 1..) = stdout.read(&mut buffer) {
+                let mut out = io::stdout();
+                let _ = out.write_all(&buffer[..len]).and_then(|()| out.flush());
+                for &byte in &buffer[..len] {
+                    if byte != b'\n' {
+                        line.push(byte);
+                        continue;
+                    }
+                    let text = strip_escapes(&String::from_utf8_lossy(&line));
+                    line.clear();
+                    if let Some(error) = stage.advance(&text) {
+                        let _ = sender.send(error);
+                    }
+                }
+            }
+        });
+        SerialWatcher { errors, thread }
+    }
+
+    /// Waits for QEMU to exit and returns its status, or `None` if `timeout` elapsed.
+    ///
+    /// QEMU is killed when the timeout elapses or GRUB reports an error.
+    fn wait(self, child: &mut Child, timeout: Option<Duration>) -> Result<Option<ExitStatus>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let status = loop {
+            if let Some(status) = child
+                .wait_timeout(Duration::from_millis(100))
+                .context("Failed to wait with timeout")?
+            {
+                break status;
+            }
+            if let Ok(error) = self.errors.try_recv() {
+                kill(child)?;
+                return Err(explain_grub_error(&error));
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                kill(child)?;
+                return Ok(None);
+            }
+        };
+        // Forward the remaining output before reporting the result.
+        let _ = self.thread.join();
+        match self.errors.try_recv() {
+            Ok(error) => Err(explain_grub_error(&error)),
+            Err(_) => Ok(Some(status)),
+        }
+    }
+}
+
+/// How far the boot has progressed, judged from the serial output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// GRUB reads its configuration and shows the menu.
+    Grub,
+    /// GRUB printed "Booting `<entry>'" and runs the commands of the entry.
+    Booting,
+    /// Something other than a GRUB error followed, so GRUB handed over to the kernel, whose
+    /// output is not checked.
+    Kernel,
+}
+
+impl Stage {
+    /// Advances the stage past `line` of the serial output, without escape sequences, and
+    /// returns the GRUB error it reports.
+    fn advance(&mut self, line: &str) -> Option<String> {
+        if *self == Stage::Kernel {
+            return None;
+        }
+        if let Some(error) = grub_error(line) {
+            return Some(error);
+        }
+        if line.starts_with("Booting `") {
+            *self = Stage::Booting;
+        } else if *self == Stage::Booting
+            && !line.is_empty()
+            && !line.starts_with("Press any key to continue")
+        {
+            *self = Stage::Kernel;
+        }
+        None
+    }
+}
+
+/// The GRUB errors that mean the kernel cannot be booted, as GRUB prints them after
+/// `error: `. A `*` stands for a file name, command or number.
+const GRUB_ERRORS: &[&str] = &[
+    "no multiboot header found.",
+    "file `*' not found.",
+    "you need to load the kernel first.",
+    "invalid arch-dependent ELF magic.",
+    "invalid arch-independent ELF magic.",
+    "unsupported tag: *",
+    "out of memory.",
+    "can't find command `*'.",
+    "syntax error.",
+];
+
+/// Returns the message if `line` of the serial output is a GRUB error.
+fn grub_error(line: &str) -> Option<String> {
+    let message = line.strip_prefix("error: ")?;
+    let known = GRUB_ERRORS
+        .iter()
+        .any(|pattern| match pattern.split_once('*') {
+            Some((prefix, suffix)) => {
+                message.len() > prefix.len() + suffix.len()
+                    && message.starts_with(prefix)
+                    && message.ends_with(suffix)
+            }
+            None => message == *pattern,
+        });
+    if known {
+        Some(message.trim_end_matches('.').to_owned())
+    } else {
+        None
+    }
+}
+
+/// Removes the terminal escape sequences GRUB draws its menu with, other control characters
+/// and the surrounding whitespace from a line of the serial output.
+fn strip_escapes(line: &str) -> String {
+    let mut text = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // A CSI sequence like `ESC [ 0 m` ends with a letter.
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+        } else if !c.is_control() {
+            text.push(c);
+        }
+    }
+    text.trim().to_owned()
+}
+
+fn explain_grub_error(message: &str) -> anyhow::Error {
+    let hint = if message.contains("no multiboot header found") {
+        "the kernel has no valid header for the protocol the grub.cfg boots it with"
+    } else if message.starts_with("file `") {
+        "a file referenced by the grub.cfg is missing from the image"
+    } else if message.contains("you need to load the kernel first") {
+        "GRUB could not load the kernel, see the error before this one"
+    } else if message.contains("ELF magic") {
+        "the kernel is not an ELF file GRUB can load"
+    } else if message.contains("can't find command") || message.contains("syntax error") {
+        "the grub.cfg is invalid, check the grub.cfg template"
+    } else {
+        "GRUB could not boot the kernel"
+    };
+    anyhow!("GRUB failed: {} ({})", hint, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_escape_sequences() {
+        let cases = [
+            (
+                "\u{1b}[0m\u{1b}[30m\u{1b}[47merror: out of memory.\r",
+                "error: out of memory.",
+            ),
+            ("  Booting `kernel'  ", "Booting `kernel'"),
+            ("\u{1b}[2J\u{1b}[1;1H", ""),
+            ("a\u{7}b\tc", "abc"),
+        ];
+        for (line, expected) in &cases {
+            assert_eq!(strip_escapes(line), *expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn recognizes_grub_errors() {
+        let cases = [
+            (
+                "error: no multiboot header found.",
+                Some("no multiboot header found"),
+            ),
+            (
+                "error: file `/boot/kernel.bin' not found.",
+                Some("file `/boot/kernel.bin' not found"),
+            ),
+            ("error: unsupported tag: 0x7", Some("unsupported tag: 0x7")),
+            (
+                "error: can't find command `multiboot3'.",
+                Some("can't find command `multiboot3'"),
+            ),
+            // A `*` must match at least one character.
+            ("error: file `' not found.", None),
+            ("error: file `/a' not found", None),
+            ("error: something else.", None),
+            ("kernel: error: out of memory.", None),
+            ("no multiboot header found.", None),
+        ];
+        for (line, expected) in &cases {
+            assert_eq!(grub_error(line).as_deref(), *expected, "{:?}", line);
+        }
+    }
+
+    /// Feeds `lines` to a fresh watcher and returns the errors it reports.
+    fn watch(lines: &[&str]) -> Vec<String> {
+        let mut stage = Stage::Grub;
+        lines
+            .iter()
+            .filter_map(|line| stage.advance(&strip_escapes(line)))
+            .collect()
+    }
+
+    #[test]
+    fn stops_watching_after_the_handover() {
+        let cases: &[(&str, &[&str], &[&str])] = &[
+            (
+                "error before booting",
+                &["GNU GRUB", "error: file `/boot/x' not found."],
+                &["file `/boot/x' not found"],
+            ),
+            (
+                "error while booting",
+                &[
+                    "Booting `kernel'",
+                    "",
+                    "error: no multiboot header found.",
+                    "Press any key to continue...",
+                ],
+                &["no multiboot header found"],
+            ),
+            (
+                "kernel output",
+                &[
+                    "Booting `kernel'",
+                    "Hello from the kernel",
+                    "error: out of memory.",
+                ],
+                &[],
+            ),
+            (
+                "escaped kernel output",
+                &[
+                    "\u{1b}[0mBooting `kernel'",
+                    "\u{1b}[0m",
+                    "kernel: ok",
+                    "error: syntax error.",
+                ],
+                &[],
+            ),
+        ];
+        for (name, lines, expected) in cases {
+            assert_eq!(watch(lines), *expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn decodes_exit_device_codes() {
+        let device = ExitDevice {
+            iobase: 0xf4,
+            iosize: 4,
+            success: 0x10,
+            failure: 0x11,
+        };
+        let cases = [
+            ("success", (0x10 << 1) | 1, 0),
+            ("failure", (0x11 << 1) | 1, 1),
+            ("unknown value", (0x42 << 1) | 1, 1),
+            ("value 0 or QEMU error", 1, 1),
+            ("clean exit without the device", 0, 1),
+            ("even code", 0x20, 1),
+        ];
+        for (name, code, expected) in &cases {
+            assert_eq!(check_exit_device(&device, *code), *expected, "{}", name);
+        }
+    }
+}
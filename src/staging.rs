@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Context, Result};
+use fs2::FileExt;
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Name of the lock file inside every staging directory.
+const LOCK_FILE: &str = ".lock";
+/// Name of the file recording the executable a staging directory belongs to.
+const EXECUTABLE_FILE: &str = "executable";
+
+/// A locked directory in which the sysroot and image of one executable are created.
+///
+/// Every executable gets its own directory below `target/grub-bootimage`, so test executables
+/// run by cargo in parallel do not overwrite each other's images. The directory is locked for
+/// as long as this value is alive; concurrent runs of the same executable wait for each other.
+#[derive(Debug)]
+pub struct StagingDir {
+    path: PathBuf,
+    name: String,
+    _lock: File,
+}
+
+impl StagingDir {
+    /// Locks the staging directory of `executable` below `root`, creating it if necessary.
+    ///
+    /// Staging directories of executables that no longer exist are removed on the way.
+    pub fn acquire(root: &Path, executable: &Path) -> Result<Self> {
+        let executable = executable
+            .canonicalize()
+            .with_context(|| format!("Failed to find executable `{}`", executable.display()))?;
+        let name = executable
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| anyhow!("Invalid executable name `{}`", executable.display()))?
+            .to_owned();
+        let path = root.join(format!(
+            "{}-{:016x}",
+            name,
+            fnv1a(executable.to_string_lossy().as_bytes())
+        ));
+
+        remove_stale(root, &path);
+
+        let lock = loop {
+            fs::create_dir_all(&path)
+                .with_context(|| format!("Failed to create `{}`", path.display()))?;
+            let lock_path = path.join(LOCK_FILE);
+            let lock = match OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path)
+            {
+                Ok(lock) => lock,
+                // Another process removed the directory before the lock file was created.
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => {
+                    return Err(error)
+                        .with_context(|| format!("Failed to open `{}`", lock_path.display()))
+                }
+            };
+            lock.lock_exclusive()
+                .with_context(|| format!("Failed to lock `{}`", lock_path.display()))?;
+            // The directory may have been removed by `clean` while we were waiting.
+            if lock_path.exists() {
+                break lock;
+            }
+        };
+
+        let recorded = executable.to_str().unwrap_or_default();
+        fs::write(path.join(EXECUTABLE_FILE), recorded)
+            .with_context(|| format!("Failed to write to `{}`", path.display()))?;
+
+        Ok(StagingDir {
+            path,
+            name,
+            _lock: lock,
+        })
+    }
+
+    /// The directory containing the boot files, which is turned into the image.
+    pub fn sysroot(&self) -> PathBuf {
+        self.path.join("sysroot")
+    }
+
+    /// The path of the bootable image with `extension`, named after the executable.
+    pub fn image_path(&self, extension: &str) -> PathBuf {
+        self.path.join(format!("{}.{}", self.name, extension))
+    }
+
+    /// The path of the copy-on-write overlay booted instead of a qcow2 image.
+    pub fn overlay_path(&self) -> PathBuf {
+        self.path.join("overlay.qcow2")
+    }
+
+    /// The directory for intermediate files, which is not part of the image.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of the writable copy of the OVMF variable store.
+    pub fn ovmf_vars_path(&self) -> PathBuf {
+        self.path.join("OVMF_VARS.fd")
+    }
+}
+
+/// Removes all staging directories below `root` that are not in use.
+///
+/// Returns the removed directories and the ones that were skipped because they are locked.
+pub fn remove_all(root: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut removed = Vec::new();
+    let mut busy = Vec::new();
+    if !root.exists() {
+        return Ok((removed, busy));
+    }
+    for entry in
+        fs::read_dir(root).with_context(|| format!("Failed to read `{}`", root.display()))?
+    {
+        let dir = entry?.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        if remove_unlocked(&dir)? {
+            removed.push(dir);
+        } else {
+            busy.push(dir);
+        }
+    }
+    // Another process may have created a staging directory since the scan, which must not
+    // be removed under it, so only an empty root is removed.
+    if let Err(err) = fs::remove_dir(root) {
+        let is_empty = fs::read_dir(root).map_or(true, |mut entries| entries.next().is_none());
+        if is_empty {
+            return Err(err).with_context(|| format!("Failed to remove `{}`", root.display()));
+        }
+    }
+    Ok((removed, busy))
+}
+
+/// Removes the staging directories whose executable has been deleted, e.g. by `cargo clean`
+/// or because the hash of a test executable changed.
+fn remove_stale(root: &Path, keep: &Path) {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for dir in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        // A directory without a lock file is still being created by another process.
+        if dir == keep || !dir.is_dir() || !dir.join(LOCK_FILE).exists() {
+            continue;
+        }
+        let executable = fs::read_to_string(dir.join(EXECUTABLE_FILE)).unwrap_or_default();
+        if !Path::new(&executable).exists() {
+            // Errors are ignored, the directory is retried on the next run.
+            let _ = remove_unlocked(&dir);
+        }
+    }
+}
+
+/// Removes `dir` unless another process holds its lock. Returns whether it was removed.
+fn remove_unlocked(dir: &Path) -> Result<bool> {
+    // The lock is held until the directory is gone.
+    let lock = File::open(dir.join(LOCK_FILE)).ok();
+    if let Some(lock) = &lock {
+        if lock.try_lock_exclusive().is_err() {
+            return Ok(false);
+        }
+    }
+    fs::remove_dir_all(dir).with_context(|| format!("Failed to remove `{}`", dir.display()))?;
+    Ok(true)
+}
+
+/// A 64-bit FNV-1a hash, which is stable across Rust versions unlike `DefaultHasher`.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,41 @@
+//! Helpers shared by the unit tests.
+
+use std::{
+    convert::TryInto,
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// A fresh directory below the system's temporary directory, removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates the directory, `name` keeps concurrently running tests apart.
+    pub fn new(name: &str) -> TempDir {
+        let dir = env::temp_dir().join(format!("grub-bootimage-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Reads the little-endian `u32` at `offset`.
+pub fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads the little-endian `u64` at `offset`.
+pub fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,179 @@
+use anyhow::{anyhow, Result};
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// An external program grub-bootimage runs, apart from QEMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Cargo,
+    GrubMkrescue,
+    GrubMkimage,
+    Xorriso,
+    Mformat,
+    Mcopy,
+    Mke2fs,
+    QemuImg,
+}
+
+impl Tool {
+    pub const ALL: &'static [Tool] = &[
+        Tool::Cargo,
+        Tool::GrubMkrescue,
+        Tool::GrubMkimage,
+        Tool::Xorriso,
+        Tool::Mformat,
+        Tool::Mcopy,
+        Tool::Mke2fs,
+        Tool::QemuImg,
+    ];
+
+    /// The name of the executable, which is also its key in the `tools` table.
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Cargo => "cargo",
+            Tool::GrubMkrescue => "grub-mkrescue",
+            Tool::GrubMkimage => "grub-mkimage",
+            Tool::Xorriso => "xorriso",
+            Tool::Mformat => "mformat",
+            Tool::Mcopy => "mcopy",
+            Tool::Mke2fs => "mke2fs",
+            Tool::QemuImg => "qemu-img",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self> {
+        Tool::ALL
+            .iter()
+            .copied()
+            .find(|tool| tool.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = Tool::ALL.iter().map(|tool| tool.name()).collect();
+                anyhow!(
+                    "unknown tool `{}` (expected one of {})",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+
+    /// The arguments that make the tool print its version.
+    pub fn version_args(self) -> &'static [&'static str] {
+        match self {
+            Tool::Xorriso => &["-version"],
+            Tool::Mke2fs => &["-V"],
+            _ => &["--version"],
+        }
+    }
+
+    /// What the tool is needed for.
+    pub fn purpose(self) -> &'static str {
+        match self {
+            Tool::Cargo => "building the kernel",
+            Tool::GrubMkrescue => "creating the ISO",
+            Tool::GrubMkimage => "creating raw disk images",
+            Tool::Xorriso => "writing the ISO for grub-mkrescue",
+            Tool::Mformat => "creating the EFI image for grub-mkrescue",
+            Tool::Mcopy => "copying the boot files into FAT raw disk images",
+            Tool::Mke2fs => "creating ext2 raw disk images",
+            Tool::QemuImg => "converting images with `convert-to` and booting qcow2 images",
+        }
+    }
+
+    /// How the tool is usually installed.
+    pub fn hint(self) -> &'static str {
+        match self {
+            Tool::Cargo => "install Rust from https://rustup.rs",
+            Tool::GrubMkrescue | Tool::GrubMkimage => {
+                "install the `grub-common` package (or `grub2-tools`)"
+            }
+            Tool::Xorriso => "install the `xorriso` package",
+            Tool::Mformat | Tool::Mcopy => "install the `mtools` package",
+            Tool::Mke2fs => "install the `e2fsprogs` package",
+            Tool::QemuImg => "install the `qemu-utils` package (or `qemu-img`)",
+        }
+    }
+
+    /// The environment variable overriding the path of the tool, e.g.
+    /// `GRUB_BOOTIMAGE_GRUB_MKRESCUE`.
+    pub fn env_var(self) -> String {
+        format!(
+            "GRUB_BOOTIMAGE_{}",
+            self.name().to_ascii_uppercase().replace('-', "_")
+        )
+    }
+}
+
+/// The environment variable overriding the QEMU system emulator.
+pub const QEMU_ENV_VAR: &str = "GRUB_BOOTIMAGE_QEMU";
+
+/// The paths of the external programs, the `tools` table of the configuration.
+#[derive(Debug, Clone, Default)]
+pub struct Tools {
+    paths: Vec<(Tool, PathBuf)>,
+}
+
+impl Tools {
+    pub fn set(&mut self, tool: Tool, path: PathBuf) {
+        self.paths.retain(|(configured, _)| *configured != tool);
+        self.paths.push((tool, path));
+    }
+
+    /// Returns the path of `tool` if it is set by its environment variable or the
+    /// configuration, in this order.
+    pub fn configured(&self, tool: Tool) -> Option<PathBuf> {
+        if let Some(path) = env::var_os(tool.env_var()).filter(|path| !path.is_empty()) {
+            return Some(PathBuf::from(path));
+        }
+        self.paths
+            .iter()
+            .find(|(configured, _)| *configured == tool)
+            .map(|(_, path)| path.clone())
+    }
+
+    /// Returns the program to run for `tool`, its name if no path is configured.
+    ///
+    /// cargo falls back to the `CARGO` variable set by cargo for the processes it runs.
+    pub fn path(&self, tool: Tool) -> PathBuf {
+        self.configured(tool)
+            .or_else(|| match tool {
+                Tool::Cargo => env::var_os("CARGO").map(PathBuf::from),
+                _ => None,
+            })
+            .unwrap_or_else(|| PathBuf::from(tool.name()))
+    }
+
+    pub fn command(&self, tool: Tool) -> Command {
+        Command::new(self.path(tool))
+    }
+
+    /// Describes the failure to launch `tool`, naming the path that was tried.
+    pub fn launch_error(&self, tool: Tool, err: io::Error) -> anyhow::Error {
+        let path = self.path(tool);
+        match err.kind() {
+            io::ErrorKind::NotFound if path.as_os_str() == tool.name() => {
+                anyhow!("{} not found in PATH, {}", tool.name(), tool.hint())
+            }
+            io::ErrorKind::NotFound => anyhow!(
+                "{} not found at `{}`, {} or correct the path in `tools` or {}",
+                tool.name(),
+                path.display(),
+                tool.hint(),
+                tool.env_var()
+            ),
+            _ => anyhow!("Failed to execute `{}`: {}", path.display(), err),
+        }
+    }
+
+    /// Returns the installation prefixes of the configured GRUB tools, e.g. `/opt/grub` for
+    /// `/opt/grub/bin/grub-mkrescue`.
+    pub fn grub_prefixes(&self) -> Vec<PathBuf> {
+        [Tool::GrubMkrescue, Tool::GrubMkimage]
+            .iter()
+            .filter_map(|&tool| self.configured(tool))
+            .filter_map(|path| path.parent().and_then(Path::parent).map(Path::to_owned))
+            .collect()
+    }
+}